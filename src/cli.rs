@@ -0,0 +1,564 @@
+//! Parses command-line arguments, and generates the help messages
+//!
+//! Arguments are split into:
+//!
+//! - long options, like `--format compact` or `--format=compact`
+//! - tags, either the legacy `-tagname` form or `--tag <name>`
+//! - words, which are either a command and its arguments, or a duration
+//!
+//! Everything after `--` is treated as a word.
+//! Any option which has an environment variable falls back to it if it isn't passed
+
+use anyhow::{bail, Error, Result};
+use std::{collections::BTreeMap, env, fmt};
+
+/// returned when the user input couldn't be parsed, causes the
+/// help message to be printed and evry to exit with the usage exit code
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// What the user asked evry to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Location,
+    Duration,
+    List,
+    Reset,
+    Migrate,
+    Status,
+    Audit,
+    Metrics,
+    Export,
+    Import,
+    Completions,
+    Outcome,
+    Help,
+    /// no command was passed, the words are a duration
+    Run,
+}
+
+/// A command, and how it's described in the help message
+#[derive(Debug)]
+pub struct CommandInfo {
+    pub command: Command,
+    pub name: &'static str,
+    /// the arguments, as shown in the usage line
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+/// every command, in the order they're shown in the help message
+pub const COMMANDS: [CommandInfo; 13] = [
+    CommandInfo {
+        command: Command::Location,
+        name: "location",
+        usage: "<-tagname>",
+        help: "prints the computed tag file location, or the data directory
+if an empty tag is passed (evry location -)",
+    },
+    CommandInfo {
+        command: Command::Duration,
+        name: "duration",
+        usage: "[--format <format>] <describe duration...>",
+        help: "lets you use this as a duration parser, without interacting with the filesystem
+it prints the parsed duration in seconds. Running with JSON mode prints more formats
+
+--format describes the duration instead, one of:
+  long (1 hour, 58 minutes, 17 seconds), compact (1h58m17s),
+  largest (~2 hours), largest:N (the largest N units) or
+  evry (1 hour 58 minutes 17 seconds, can be passed back to evry)
+Set EVRY_DURATION_FORMAT to change how durations are described in debug output",
+    },
+    CommandInfo {
+        command: Command::List,
+        name: "list",
+        usage: "[-namespace]",
+        help: "prints the name of every tag, or every tag in a namespace",
+    },
+    CommandInfo {
+        command: Command::Reset,
+        name: "reset",
+        usage: "<-tagname|-namespace>",
+        help: "removes a tag file (so the next run succeeds), or every tag in a namespace",
+    },
+    CommandInfo {
+        command: Command::Migrate,
+        name: "migrate",
+        usage: "",
+        help: "moves tags from the data directory older versions of evry used
+(~/.local/share/evry/data) to the current one (~/.local/state/evry/tags)",
+    },
+    CommandInfo {
+        command: Command::Status,
+        name: "status",
+        usage: "[job file]",
+        help: "prints a table describing every job in a job file: when it last ran,
+whether its waiting, due or overdue, when it'll next run and its last outcome.
+The job file defaults to --job-file, or ~/.config/evry/jobs. Looks like:
+
+  [backup/photos]
+  every = 1 week
+  command = rsync -a ~/Pictures remote:Pictures",
+    },
+    CommandInfo {
+        command: Command::Audit,
+        name: "audit",
+        usage: "[--grace <factor>] [job file]",
+        help: "lists tags which haven't run in their interval times a grace factor
+(default 3, so a daily job which hasn't run in 3 days), exiting with code 3
+if there are any. Uses the intervals from the job file, and the interval each
+tag was last run with for tags which aren't in the job file",
+    },
+    CommandInfo {
+        command: Command::Metrics,
+        name: "metrics",
+        usage: "<output file>",
+        help: "writes metrics for every tag (when it last ran, last succeeded,
+its interval and last exit code) to a file for the node_exporter textfile
+collector. The file can also be set with --metrics-file, or '-' for stdout",
+    },
+    CommandInfo {
+        command: Command::Export,
+        name: "export",
+        usage: "systemd <target directory> [job file]",
+        help: "generates a .service and .timer unit for each job in the job
+file which has a command, and writes them to the target directory for review",
+    },
+    CommandInfo {
+        command: Command::Import,
+        name: "import",
+        usage: "<anacrontab|crontab> [file]",
+        help: "converts an anacrontab or crontab (read from stdin if a file isn't
+passed) into a job file, printed to stdout. Entries which can't be
+converted are skipped with a warning",
+    },
+    CommandInfo {
+        command: Command::Completions,
+        name: "completions",
+        usage: "<bash|zsh|fish>",
+        help: "prints a completion script for your shell, which completes
+commands, duration units and the names of your tags:
+  source <(evry completions bash)",
+    },
+    CommandInfo {
+        command: Command::Outcome,
+        name: "outcome",
+        usage: "<exit code> <-tagname>",
+        help: "saves the exit code of a job, shown by status:
+  evry 1 week -backup/photos && { rsync ...; evry outcome $? -backup/photos; }",
+    },
+    CommandInfo {
+        command: Command::Help,
+        name: "help",
+        usage: "[command]",
+        help: "prints this help message, or the help for a command",
+    },
+];
+
+/// What an option expects after it
+#[derive(Debug, PartialEq)]
+pub enum Takes {
+    /// a flag, like `--debug`
+    Nothing,
+    /// a value, like `--format compact` or `--format=compact`
+    Value(&'static str),
+    /// an optional value, which has to be passed like `--json=ndjson`
+    OptionalValue(&'static str),
+}
+
+/// A long option, and the environment variable it's equivalent to
+#[derive(Debug)]
+pub struct Opt {
+    /// name of the option, without the leading '--'
+    pub name: &'static str,
+    pub takes: Takes,
+    pub env: Option<&'static str>,
+    pub help: &'static str,
+}
+
+/// every option, in the order they're shown in the help message
+pub const OPTIONS: [Opt; 18] = [
+    Opt {
+        name: "tag",
+        takes: Takes::Value("name"),
+        env: None,
+        help: "the tag, same as -name but allows names which start with a '-'",
+    },
+    Opt {
+        name: "dir",
+        takes: Takes::Value("directory"),
+        env: Some("EVRY_DIR"),
+        help: "directory to save tags in, instead of the nearest .evry directory or ~/.local/state/evry/tags",
+    },
+    Opt {
+        name: "job-file",
+        takes: Takes::Value("file"),
+        env: Some("EVRY_JOB_FILE"),
+        help: "job file to use if one isn't passed, defaults to ~/.config/evry/jobs",
+    },
+    Opt {
+        name: "new",
+        takes: Takes::Nothing,
+        env: None,
+        help: "allow creating a tag which doesn't exist yet in strict mode",
+    },
+    Opt {
+        name: "strict",
+        takes: Takes::Nothing,
+        env: Some("EVRY_STRICT"),
+        help: "don't create tags which aren't in the job file unless --new is passed",
+    },
+    Opt {
+        name: "first-run",
+        takes: Takes::Value("policy"),
+        env: Some("EVRY_FIRST_RUN"),
+        help: "when a tag doesn't exist yet: run (now, the default), defer (one interval) or random (within the interval)",
+    },
+    Opt {
+        name: "clock-skew",
+        takes: Takes::Value("policy"),
+        env: Some("EVRY_CLOCK_SKEW"),
+        help: "when a tag last ran in the future: just-run (the default), never-run or error",
+    },
+    Opt {
+        name: "monotonic",
+        takes: Takes::Nothing,
+        env: Some("EVRY_MONOTONIC"),
+        help: "measure intervals with CLOCK_BOOTTIME if the tag last ran in the same boot, so clock changes don't affect them",
+    },
+    Opt {
+        name: "active",
+        takes: Takes::Nothing,
+        env: Some("EVRY_ACTIVE"),
+        help: "only count the time the machine was running (not suspended) towards the interval",
+    },
+    Opt {
+        name: "window",
+        takes: Takes::Value("start-end"),
+        env: Some("EVRY_WINDOW"),
+        help: "only run in this time of day, like 01:00-05:00, even if the tag is due before then",
+    },
+    Opt {
+        name: "dry-run",
+        takes: Takes::Nothing,
+        env: Some("EVRY_DRY_RUN"),
+        help: "decide whether the tag should run, but don't write to the tag file",
+    },
+    Opt {
+        name: "debug",
+        takes: Takes::Nothing,
+        env: Some("EVRY_DEBUG"),
+        help: "print debug messages to stderr",
+    },
+    Opt {
+        name: "json",
+        takes: Takes::OptionalValue("format"),
+        env: Some("EVRY_JSON"),
+        help: "print debug messages as JSON, as a list (the default), ndjson or an object",
+    },
+    Opt {
+        name: "format",
+        takes: Takes::Value("format"),
+        env: Some("EVRY_DURATION_FORMAT"),
+        help: "how to describe durations: long, compact, largest, largest:N or evry",
+    },
+    Opt {
+        name: "grace",
+        takes: Takes::Value("factor"),
+        env: Some("EVRY_AUDIT_GRACE"),
+        help: "how many intervals a tag can go without running before audit reports it (default 3)",
+    },
+    Opt {
+        name: "metrics-file",
+        takes: Takes::Value("file"),
+        env: Some("EVRY_METRICS_FILE"),
+        help: "file the metrics command writes to if one isn't passed",
+    },
+    Opt {
+        name: "parse-error-log",
+        takes: Takes::Value("file"),
+        env: Some("EVRY_PARSE_ERROR_LOG"),
+        help: "append durations which couldn't be parsed to this file",
+    },
+    Opt {
+        name: "help",
+        takes: Takes::Nothing,
+        env: None,
+        help: "print help, for a command if one is passed",
+    },
+];
+
+/// finds an option by name, panics if it doesn't exist since that's a typo in evry itself
+fn option(name: &str) -> &'static Opt {
+    OPTIONS
+        .iter()
+        .find(|opt| opt.name == name)
+        .unwrap_or_else(|| panic!("unknown option '{}'", name))
+}
+
+/// The arguments, split into words, tags and options
+#[derive(Debug, Default, PartialEq)]
+pub struct Parsed {
+    /// the command and its arguments, or the duration
+    pub words: Vec<String>,
+    /// tag names, without their prefix
+    pub tags: Vec<String>,
+    /// options which were passed, flags have an empty value
+    options: BTreeMap<&'static str, String>,
+}
+
+impl Parsed {
+    /// if the option was passed on the command line
+    pub fn passed(&self, name: &str) -> bool {
+        self.options.contains_key(option(name).name)
+    }
+
+    /// the value of an option, or its environment variable if it wasn't passed
+    pub fn value(&self, name: &str) -> Option<String> {
+        let opt = option(name);
+        match self.options.get(opt.name) {
+            Some(value) => Some(value.clone()),
+            None => opt.env.and_then(|env| env::var(env).ok()),
+        }
+    }
+
+    /// if a flag was passed, or its environment variable is set
+    pub fn flag(&self, name: &str) -> bool {
+        self.value(name).is_some()
+    }
+
+    /// the command, from the first word
+    pub fn command(&self) -> Command {
+        match self.words.first() {
+            Some(word) => COMMANDS
+                .iter()
+                .find(|info| info.name == word)
+                .map_or(Command::Run, |info| info.command),
+            None => Command::Run,
+        }
+    }
+
+    /// if the user asked for help, the command they asked about (if any)
+    ///
+    /// Handles `evry help`, `evry help status`, `evry --help` and `evry status --help`
+    pub fn help_topic(&self) -> Result<Option<Option<&'static CommandInfo>>, Error> {
+        let topic = match (self.command(), self.passed("help")) {
+            (Command::Help, _) => self.words.get(1),
+            (Command::Run, true) => None,
+            (_, true) => self.words.first(),
+            (_, false) => return Ok(None),
+        };
+        match topic {
+            None => Ok(Some(None)),
+            Some(name) => match COMMANDS.iter().find(|info| info.name == name) {
+                Some(info) => Ok(Some(Some(info))),
+                None => bail!(UsageError(format!("Unknown command '{}'", name))),
+            },
+        }
+    }
+}
+
+/// splits the arguments (without the binary name) into words, tags and options
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Parsed, Error> {
+    let mut parsed = Parsed::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            parsed.words.extend(args.by_ref());
+            break;
+        }
+        let long = match arg.strip_prefix("--") {
+            Some(long) => long,
+            None => {
+                // 'evry metrics -' prints the metrics, '-' isn't an (empty) tag
+                if arg == "-" && parsed.command() == Command::Metrics {
+                    parsed.words.push(arg);
+                    continue;
+                }
+                match arg.strip_prefix('-') {
+                    Some(tag) => parsed.tags.push(tag.to_string()),
+                    None => parsed.words.push(arg),
+                }
+                continue;
+            }
+        };
+        let (name, inline_value) = match long.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (long, None),
+        };
+        let opt = OPTIONS
+            .iter()
+            .find(|opt| opt.name == name)
+            .ok_or_else(|| UsageError(format!("Unknown option '--{}'", name)))?;
+        let value = match (&opt.takes, inline_value) {
+            (Takes::Nothing, Some(_)) => {
+                bail!(UsageError(format!("--{} doesn't take a value", name)))
+            }
+            (Takes::Nothing, None) | (Takes::OptionalValue(_), None) => String::new(),
+            (Takes::Value(_), None) => args
+                .next()
+                .ok_or_else(|| UsageError(format!("--{} requires a value", name)))?,
+            (_, Some(value)) => value,
+        };
+        if opt.name == "tag" {
+            parsed.tags.push(value);
+        } else {
+            parsed.options.insert(opt.name, value);
+        }
+    }
+    Ok(parsed)
+}
+
+/// the usage line for a command
+fn usage(info: &CommandInfo) -> String {
+    format!("evry {} {}", info.name, info.usage)
+        .trim_end()
+        .to_string()
+}
+
+/// the help message, printed by 'evry help' or when the arguments couldn't be parsed
+pub fn help(exit_codes: &[(i32, &str)]) -> String {
+    let mut out = String::from(
+        "A tool to manually run commands -- periodically.
+Uses shell exit codes to determine control flow in shell scripts
+
+Usage:
+  evry <describe duration>... <-tagname> [options]
+",
+    );
+    for info in &COMMANDS {
+        out.push_str(&format!("  {}\n", usage(info)));
+    }
+    out.push_str(
+        "
+Best explained with an example:
+
+evry 2 weeks -scrapesite && wget \"https://\" -o ....
+
+In other words, run the wget command every 2 weeks.
+
+evry exits with an unsuccessful exit code if the command has
+been run in the last 2 weeks, which means the wget command wouldn't run.
+
+When evry exits with a successful exit code, it saves the current time
+to a metadata file for that tag (-scrapesite). That way, when evry
+is run again with that tag, it can compare the current time against that file.
+
+Tags can be grouped into namespaces using a '/', like -backup/photos.
+Each namespace is saved as a subdirectory of the data directory
+
+With --strict, a tag which doesn't exist yet isn't created (and evry
+exits with code 1) unless --new is passed, or its listed in the job
+file, so a typo doesn't run the job. Similar tag names are suggested
+
+Run 'evry help <command>' for more information about a command
+
+Options:
+",
+    );
+    let rows: Vec<(String, String)> = OPTIONS
+        .iter()
+        .map(|opt| {
+            let name = match opt.takes {
+                Takes::Nothing => format!("--{}", opt.name),
+                Takes::Value(value) => format!("--{} <{}>", opt.name, value),
+                Takes::OptionalValue(value) => format!("--{}[=<{}>]", opt.name, value),
+            };
+            let description = match opt.env {
+                Some(env) => format!("{} [env: {}]", opt.help, env),
+                None => opt.help.to_string(),
+            };
+            (name, description)
+        })
+        .collect();
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, description) in rows {
+        out.push_str(&format!(
+            "  {:width$}  {}\n",
+            name,
+            description,
+            width = width
+        ));
+    }
+    out.push_str("\nExit codes:\n");
+    for (code, description) in exit_codes {
+        out.push_str(&format!("  {:<3} {}\n", code, description));
+    }
+    out.push_str("\nSee https://github.com/seanbreckenridge/evry for more examples.");
+    out
+}
+
+/// the help message for a single command
+pub fn command_help(info: &CommandInfo) -> String {
+    format!("Usage:\n  {}\n\n{} {}", usage(info), info.name, info.help)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse(args(&[
+            "2",
+            "weeks",
+            "-scrapesite",
+            "--format",
+            "compact",
+            "--json=ndjson",
+            "--tag",
+            "-weird",
+            "--debug",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.words, args(&["2", "weeks"]));
+        assert_eq!(parsed.tags, args(&["scrapesite", "-weird"]));
+        assert_eq!(parsed.value("format"), Some("compact".to_string()));
+        assert_eq!(parsed.value("json"), Some("ndjson".to_string()));
+        assert!(parsed.flag("debug"));
+        assert!(!parsed.passed("new"));
+        assert_eq!(parsed.command(), Command::Run);
+
+        let parsed = parse(args(&["status", "--", "--jobs"])).unwrap();
+        assert_eq!(parsed.command(), Command::Status);
+        assert_eq!(parsed.words, args(&["status", "--jobs"]));
+
+        let parsed = parse(args(&["metrics", "-"])).unwrap();
+        assert_eq!(parsed.words, args(&["metrics", "-"]));
+        assert!(parsed.tags.is_empty());
+        let parsed = parse(args(&["location", "-"])).unwrap();
+        assert_eq!(parsed.tags, args(&[""]));
+
+        assert!(parse(args(&["--dry"])).is_err());
+        assert!(parse(args(&["--format"])).is_err());
+        assert!(parse(args(&["--new=yes"])).is_err());
+    }
+
+    #[test]
+    fn test_help_topic() {
+        let topic = |a: &[&str]| {
+            parse(args(a))
+                .unwrap()
+                .help_topic()
+                .map(|topic| topic.map(|info| info.map(|info| info.name)))
+        };
+        assert_eq!(topic(&["1d", "-tag"]).unwrap(), None);
+        assert_eq!(topic(&["help"]).unwrap(), Some(None));
+        assert_eq!(topic(&["--help"]).unwrap(), Some(None));
+        assert_eq!(topic(&["help", "status"]).unwrap(), Some(Some("status")));
+        assert_eq!(topic(&["audit", "--help"]).unwrap(), Some(Some("audit")));
+        assert!(topic(&["help", "bogus"]).is_err());
+    }
+}
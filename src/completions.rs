@@ -0,0 +1,227 @@
+//! Generates shell completion scripts, used by `evry completions`
+//!
+//! Tags are completed by running `evry list` when completing, so the scripts
+//! don't need to be regenerated as tags are created
+
+use anyhow::{bail, Error, Result};
+
+use crate::cli::{Takes, COMMANDS, OPTIONS};
+
+/// the grammar used to parse durations, to pull the time units from
+#[doc(hidden)]
+const GRAMMAR: &str = include_str!("time.pest");
+
+/// the rules in the grammar which define a time unit
+#[doc(hidden)]
+const UNIT_RULES: [&str; 8] = [
+    "business_day",
+    "year",
+    "month",
+    "week",
+    "day",
+    "hour",
+    "minute",
+    "second",
+];
+
+/// shells `evry completions` can generate scripts for
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// every time unit the grammar accepts, singular and plural
+///
+/// Plurals of single-letter abbreviations are skipped, since `ms` would complete to minutes.
+/// Spellings with a space (`business day`) are skipped, since they'd complete as two words
+pub fn units() -> Vec<String> {
+    let mut units = vec![];
+    for line in GRAMMAR.lines() {
+        let definition = match line.split_once('=') {
+            Some((rule, definition)) if UNIT_RULES.contains(&rule.trim()) => definition,
+            _ => continue,
+        };
+        // the spellings are the quoted strings, like { "hour" | "hr" | "h" }
+        for spelling in definition.split('"').skip(1).step_by(2) {
+            if spelling.contains(' ') {
+                continue;
+            }
+            units.push(spelling.to_string());
+            if spelling.len() > 1 {
+                units.push(format!("{}s", spelling));
+            }
+        }
+    }
+    units
+}
+
+/// every command, separated by spaces
+fn subcommands() -> String {
+    COMMANDS
+        .iter()
+        .map(|info| info.name)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// every long option, like `--format`, separated by spaces
+fn long_options() -> String {
+    OPTIONS
+        .iter()
+        .map(|opt| format!("--{}", opt.name))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn bash(units: &str) -> String {
+    format!(
+        r#"_evry() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$cur" in
+        --*)
+            COMPREPLY=($(compgen -W "{long_options}" -- "$cur"))
+            return ;;
+        -*)
+            # tag names can contain spaces, so split them on newlines
+            local IFS=$'\n'
+            COMPREPLY=($(compgen -P - -W "$(evry list 2>/dev/null)" -- "${{cur#-}}"))
+            return ;;
+    esac
+    case "$prev" in
+        completions) COMPREPLY=($(compgen -W "{shells}" -- "$cur")) ;;
+        export) COMPREPLY=($(compgen -W "systemd" -- "$cur")) ;;
+        import) COMPREPLY=($(compgen -W "anacrontab crontab" -- "$cur")) ;;
+        help) COMPREPLY=($(compgen -W "{subcommands}" -- "$cur")) ;;
+        *)
+            if [[ "$prev" =~ ^[0-9_]+$ ]]; then
+                COMPREPLY=($(compgen -W "{units}" -- "$cur"))
+            elif [[ $COMP_CWORD -eq 1 ]]; then
+                COMPREPLY=($(compgen -W "{subcommands}" -- "$cur"))
+            fi ;;
+    esac
+}}
+complete -F _evry evry
+"#,
+        long_options = long_options(),
+        shells = SHELLS.join(" "),
+        units = units,
+        subcommands = subcommands(),
+    )
+}
+
+fn zsh(units: &str) -> String {
+    format!(
+        r#"#compdef evry
+
+_evry() {{
+    local -a tags
+    case "$PREFIX" in
+        --*)
+            compadd -- {long_options}
+            return ;;
+        -*)
+            tags=(${{(f)"$(evry list 2>/dev/null)"}})
+            compadd -- "${{(@)tags/#/-}}"
+            return ;;
+    esac
+    case "$words[CURRENT-1]" in
+        completions) compadd -- {shells} ;;
+        export) compadd -- systemd ;;
+        import) compadd -- anacrontab crontab ;;
+        help) compadd -- {subcommands} ;;
+        <->) compadd -- {units} ;;
+        *)
+            if (( CURRENT == 2 )); then
+                compadd -- {subcommands}
+            fi ;;
+    esac
+}}
+
+if [ "$funcstack[1]" = "_evry" ]; then
+    _evry "$@"
+else
+    compdef _evry evry
+fi
+"#,
+        long_options = long_options(),
+        shells = SHELLS.join(" "),
+        units = units,
+        subcommands = subcommands(),
+    )
+}
+
+fn fish(units: &str) -> String {
+    let mut script = String::from(
+        "complete -c evry -f
+complete -c evry -n 'string match -q -- \"-*\" (commandline -ct)' -a '(evry list 2>/dev/null | string replace -r \"^\" -)'
+complete -c evry -n 'string match -qr -- \"^[0-9_]+\\$\" (commandline -opc)[-1]' -a '",
+    );
+    script.push_str(units);
+    script.push_str("'\n");
+    script.push_str(&format!(
+        "complete -c evry -n '__fish_use_subcommand' -a '{}'\n",
+        subcommands()
+    ));
+    script.push_str(&format!(
+        "complete -c evry -n '__fish_seen_subcommand_from help' -a '{}'\n",
+        subcommands()
+    ));
+    script.push_str(&format!(
+        "complete -c evry -n '__fish_seen_subcommand_from completions' -a '{}'\n",
+        SHELLS.join(" ")
+    ));
+    script.push_str("complete -c evry -n '__fish_seen_subcommand_from export' -a 'systemd'\n");
+    script.push_str(
+        "complete -c evry -n '__fish_seen_subcommand_from import' -a 'anacrontab crontab'\n",
+    );
+    for opt in OPTIONS {
+        script.push_str(&format!("complete -c evry -l {}", opt.name));
+        if let Takes::Value(_) = opt.takes {
+            script.push_str(" -r");
+        }
+        script.push_str(&format!(" -d '{}'\n", opt.help.replace('\'', "\\'")));
+    }
+    script
+}
+
+/// generates the completion script for a shell
+pub fn script(shell: &str) -> Result<String, Error> {
+    let units = units().join(" ");
+    Ok(match shell {
+        "bash" => bash(&units),
+        "zsh" => zsh(&units),
+        "fish" => fish(&units),
+        other => bail!(
+            "Unknown shell '{}', expected one of: {}",
+            other,
+            SHELLS.join(", ")
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_schedule;
+
+    #[test]
+    fn test_units() {
+        let units = units();
+        assert!(units.contains(&"hours".to_string()));
+        assert!(units.contains(&"min".to_string()));
+        assert!(!units.contains(&"ms".to_string()));
+        // every unit we complete should be accepted by the parser
+        for unit in units {
+            assert!(parse_schedule(&format!("2 {}", unit)).is_ok(), "{}", unit);
+        }
+    }
+
+    #[test]
+    fn test_script() {
+        for shell in SHELLS {
+            let script = script(shell).unwrap();
+            assert!(script.contains("evry list"));
+            assert!(script.contains("completions"));
+            assert!(script.contains(" weeks "));
+        }
+        assert!(script("powershell").is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Converts jobs from a job file into systemd timer/service units, used by `evry export systemd`
+
+use anyhow::{Context, Error, Result};
+use std::{fs::create_dir_all, fs::write, path::Path};
+
+use crate::{
+    jobs::Job,
+    parser::{DAY_MILLIS, HOUR_MILLIS, WEEK_MILLIS},
+    schedule::{Schedule, Weekdays},
+};
+
+/// durations which can be represented exactly with an `OnCalendar=` shorthand
+#[doc(hidden)]
+const CALENDAR_SHORTHANDS: [(u128, &str); 3] = [
+    (HOUR_MILLIS, "hourly"),
+    (DAY_MILLIS, "daily"),
+    (WEEK_MILLIS, "weekly"),
+];
+
+/// escapes a tag name into a unit name, like `systemd-escape` does:
+/// namespace separators become '-', and anything else which isn't
+/// allowed in a unit name is escaped as `\xNN`
+///
+/// Converts `backup/photos` to `evry-backup-photos`
+pub fn unit_name(tag: &str) -> String {
+    let mut name = String::from("evry-");
+    for (i, byte) in tag.bytes().enumerate() {
+        match byte {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => name.push(byte as char),
+            _ => name.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    name
+}
+
+/// escapes '%' specifiers in a line of a unit file (e.g. `Description=`)
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// quotes an argument in an `Exec*=` line
+///
+/// systemd expands '%' specifiers and '$' variables itself, so those are doubled
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "$$");
+    format!("\"{}\"", escape_specifiers(&escaped))
+}
+
+/// systemd's names for the days of the week, starting with Monday
+#[doc(hidden)]
+const SYSTEMD_DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// how long after boot a timer for a plain interval first runs, if the interval is longer.
+/// `OnUnitActiveSec=` only counts from the last run, so without this a job would never
+/// run on a machine which is rebooted more often than the interval
+#[doc(hidden)]
+const BOOT_DELAY_SECS: u128 = 15 * 60;
+
+/// how a timer schedules a job
+#[derive(Debug, PartialEq)]
+enum Trigger {
+    /// an `OnCalendar=` expression which runs exactly as often as the job
+    Calendar(String),
+    /// a plain interval, in seconds
+    Interval(u128),
+    /// systemd can't express the schedule (a window, or a count of business days), so
+    /// the job is polled with this `OnCalendar=` shorthand and evry decides whether it's due
+    Checked(&'static str),
+}
+
+/// the days of the week in an `OnCalendar=` expression, like `Mon..Fri` or `Mon,Wed,Fri`
+fn calendar_days(days: Weekdays) -> String {
+    // Monday is 1, Sunday 0
+    let included: Vec<bool> = (1..=7).map(|day| days.contains(day % 7)).collect();
+    let mut parts = vec![];
+    let mut day = 0;
+    while day < 7 {
+        if !included[day] {
+            day += 1;
+            continue;
+        }
+        let mut last = day;
+        while last + 1 < 7 && included[last + 1] {
+            last += 1;
+        }
+        match last - day {
+            0 => parts.push(SYSTEMD_DAYS[day].to_string()),
+            1 => parts.push(format!("{},{}", SYSTEMD_DAYS[day], SYSTEMD_DAYS[last])),
+            _ => parts.push(format!("{}..{}", SYSTEMD_DAYS[day], SYSTEMD_DAYS[last])),
+        }
+        day = last + 1;
+    }
+    parts.join(",")
+}
+
+/// an `OnCalendar=` expression for a daily schedule with a time of day or days of the
+/// week, like `Mon..Fri *-*-* 09:00:00 Europe/Berlin`. `None` if it runs less often than
+/// daily, has a window or counts more than one business day, which systemd can't express
+fn calendar_expression(schedule: &Schedule) -> Option<String> {
+    if schedule.every != DAY_MILLIS || schedule.window.is_some() || schedule.business_days > 1 {
+        return None;
+    }
+    // a single business day is every weekday, unless the days were listed with `on`
+    let days = match (schedule.days, schedule.business_days) {
+        (Some(days), _) => Some(days),
+        (None, 1) => Some(Weekdays::from_names(["mon", "tue", "wed", "thu", "fri"]).ok()?),
+        (None, _) => None,
+    };
+    let at = schedule.at.unwrap_or(0);
+    let mut expression = format!(
+        "*-*-* {:02}:{:02}:{:02}",
+        at / 3600,
+        (at % 3600) / 60,
+        at % 60
+    );
+    if let Some(days) = days {
+        expression = format!("{} {}", calendar_days(days), expression);
+    }
+    if let Some(timezone) = &schedule.timezone {
+        expression = format!("{} {}", expression, timezone);
+    }
+    Some(expression)
+}
+
+/// how the timer for a job schedules it
+fn trigger(job: &Job) -> Trigger {
+    let schedule = &job.schedule;
+    if !schedule.is_calendar() {
+        return match CALENDAR_SHORTHANDS
+            .iter()
+            .find(|(ms, _)| *ms == schedule.every)
+        {
+            Some((_, shorthand)) => Trigger::Calendar(shorthand.to_string()),
+            None => Trigger::Interval(schedule.every.div_ceil(1000).max(1)),
+        };
+    }
+    match calendar_expression(schedule) {
+        Some(expression) => Trigger::Calendar(expression),
+        None if schedule.every >= DAY_MILLIS
+            && schedule.window.is_none()
+            && schedule.at.is_none() =>
+        {
+            Trigger::Checked("hourly")
+        }
+        None => Trigger::Checked("minutely"),
+    }
+}
+
+/// an `ExecCondition=` line which asks evry whether the job is due, so the
+/// service is skipped (rather than failing) when it isn't
+fn exec_condition(job: &Job, evry: &str) -> String {
+    let mut condition = format!("ExecCondition={} {}", quote(evry), quote(&job.every));
+    if let Some(window) = &job.schedule.window {
+        condition.push_str(&format!(" --window={}", window.describe()));
+    }
+    format!("{} --tag {}\n", condition, quote(&job.tag))
+}
+
+/// the `[Timer]` lines which schedule the job
+///
+/// `Persistent=true` runs a calendar timer which was missed while the machine was off at the
+/// next boot. A plain interval first runs shortly after boot, then the interval after each run
+fn timer_schedule(trigger: &Trigger) -> String {
+    match trigger {
+        Trigger::Calendar(calendar) => {
+            format!(
+                "OnCalendar={}\nPersistent=true",
+                escape_specifiers(calendar)
+            )
+        }
+        Trigger::Checked(shorthand) => format!("OnCalendar={}\nPersistent=true", shorthand),
+        Trigger::Interval(secs) => format!(
+            "OnBootSec={}s\nOnUnitActiveSec={}s",
+            (*secs).min(BOOT_DELAY_SECS),
+            secs
+        ),
+    }
+}
+
+/// A pair of units generated for a job
+#[derive(Debug, PartialEq)]
+pub struct Units {
+    /// name of the units, without the `.service`/`.timer` suffix
+    pub name: String,
+    pub service: String,
+    pub timer: String,
+}
+
+/// generates the service/timer units for a job, `None` if the job doesn't have a command
+///
+/// `evry` is the path to the evry binary, which checks whether the job is due if
+/// systemd can't schedule it by itself
+pub fn systemd_units(job: &Job, evry: &str) -> Option<Units> {
+    let command = job.command.as_ref()?;
+    let name = unit_name(&job.tag);
+    let trigger = trigger(job);
+    let service = format!(
+        "[Unit]
+Description=evry job {tag}
+
+[Service]
+Type=oneshot
+{condition}ExecStart=/bin/sh -c {exec}
+",
+        tag = escape_specifiers(&job.tag),
+        condition = match trigger {
+            Trigger::Checked(_) => exec_condition(job, evry),
+            _ => String::new(),
+        },
+        exec = quote(command)
+    );
+    let timer = format!(
+        "[Unit]
+Description=Run evry job {tag} every {every}
+
+[Timer]
+{schedule}
+Unit={name}.service
+
+[Install]
+WantedBy=timers.target
+",
+        tag = escape_specifiers(&job.tag),
+        every = escape_specifiers(&job.every),
+        schedule = timer_schedule(&trigger),
+        name = name
+    );
+    Some(Units {
+        name,
+        service,
+        timer,
+    })
+}
+
+/// writes the units to a directory, returning the paths that were written
+pub fn write_units(units: &Units, dir: &Path) -> Result<Vec<String>, Error> {
+    create_dir_all(dir).context(format!("Could not create directory {}", dir.display()))?;
+    let mut written = vec![];
+    for (suffix, contents) in [("service", &units.service), ("timer", &units.timer)] {
+        let path = dir.join(format!("{}.{}", units.name, suffix));
+        write(&path, contents).context(format!("Could not write to {}", path.display()))?;
+        written.push(path.display().to_string());
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_name() {
+        assert_eq!(unit_name("backup/photos"), "evry-backup-photos");
+        assert_eq!(unit_name("scrape-site"), "evry-scrape\\x2dsite");
+        assert_eq!(unit_name(".hidden tag"), "evry-\\x2ehidden\\x20tag");
+    }
+
+    #[test]
+    fn test_systemd_units() {
+        let mut job = Job {
+            tag: "backup/photos".to_string(),
+            every: "1 day".to_string(),
+            schedule: Schedule {
+                every: DAY_MILLIS,
+                ..Default::default()
+            },
+            command: Some("rsync -a \"$HOME/Pictures\" remote:".to_string()),
+        };
+        let units = systemd_units(&job, "/usr/bin/evry").unwrap();
+        assert_eq!(units.name, "evry-backup-photos");
+        assert_eq!(
+            units.service,
+            "[Unit]
+Description=evry job backup/photos
+
+[Service]
+Type=oneshot
+ExecStart=/bin/sh -c \"rsync -a \\\"$$HOME/Pictures\\\" remote:\"
+"
+        );
+        assert!(units.timer.contains("OnCalendar=daily\nPersistent=true\n"));
+        assert!(units.timer.contains("Unit=evry-backup-photos.service\n"));
+
+        // plain intervals are counted from the last run
+        job.tag = "100% backup".to_string();
+        job.every = "2 days".to_string();
+        job.schedule.every = 2 * DAY_MILLIS;
+        let units = systemd_units(&job, "/home/user/.cargo/bin/evry").unwrap();
+        assert!(units
+            .service
+            .contains("Description=evry job 100%% backup\n"));
+        assert!(!units.service.contains("ExecCondition="));
+        assert_eq!(
+            units.timer,
+            "[Unit]
+Description=Run evry job 100%% backup every 2 days
+
+[Timer]
+OnBootSec=900s
+OnUnitActiveSec=172800s
+Unit=evry-100\\x25\\x20backup.service
+
+[Install]
+WantedBy=timers.target
+"
+        );
+        job.schedule.every = 30 * 1000;
+        let units = systemd_units(&job, "evry").unwrap();
+        assert!(units.timer.contains("OnBootSec=30s\nOnUnitActiveSec=30s\n"));
+
+        // a time of day and days of the week are calendar expressions
+        for (every, calendar) in [
+            (
+                "1 day at 02:30 in Europe/Berlin",
+                "*-*-* 02:30:00 Europe/Berlin",
+            ),
+            ("1 business day at 09:00", "Mon..Fri *-*-* 09:00:00"),
+            (
+                "1 day on mon,wed,fri,sat,sun",
+                "Mon,Wed,Fri..Sun *-*-* 00:00:00",
+            ),
+            ("1 day on tue,wed at 18:15", "Tue,Wed *-*-* 18:15:00"),
+        ] {
+            job.every = every.to_string();
+            job.schedule = crate::parser::parse_schedule(every).unwrap();
+            let units = systemd_units(&job, "evry").unwrap();
+            assert!(!units.service.contains("ExecCondition="), "{}", every);
+            assert!(
+                units
+                    .timer
+                    .contains(&format!("OnCalendar={}\nPersistent=true\n", calendar)),
+                "{}: {}",
+                every,
+                units.timer
+            );
+        }
+
+        // evry decides when jobs systemd can't schedule run
+        job.every = "1 day".to_string();
+        job.schedule = Schedule {
+            every: DAY_MILLIS,
+            window: Some("01:00-05:00".parse().unwrap()),
+            ..Default::default()
+        };
+        let units = systemd_units(&job, "evry").unwrap();
+        assert!(units.service.contains(
+            "ExecCondition=\"evry\" \"1 day\" --window=01:00-05:00 --tag \"100%% backup\"\n"
+        ));
+        assert!(units
+            .timer
+            .contains("OnCalendar=minutely\nPersistent=true\n"));
+
+        job.every = "2 business days".to_string();
+        job.schedule = crate::parser::parse_schedule(&job.every).unwrap();
+        let units = systemd_units(&job, "evry").unwrap();
+        assert!(units
+            .service
+            .contains("ExecCondition=\"evry\" \"2 business days\" --tag \"100%% backup\"\n"));
+        assert!(units.timer.contains("OnCalendar=hourly\nPersistent=true\n"));
+
+        job.every = "2 days at 02:30".to_string();
+        job.schedule = crate::parser::parse_schedule(&job.every).unwrap();
+        let units = systemd_units(&job, "evry").unwrap();
+        assert!(units.service.contains("ExecCondition="));
+        assert!(units
+            .timer
+            .contains("OnCalendar=minutely\nPersistent=true\n"));
+
+        job.command = None;
+        assert_eq!(systemd_units(&job, "evry"), None);
+    }
+}
@@ -3,56 +3,655 @@
 //! This includes functions resolve where to,
 //! write to, and read from tag files
 
-use anyhow::{Context, Error, Result};
-use app_dirs::{self, AppDataType, AppInfo};
+use anyhow::{bail, Context, Error, Result};
 use std::{
-    fs::{create_dir_all, read_to_string, File},
-    io::{BufWriter, Write},
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{copy, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, File},
+    io::{self, BufWriter, Write},
     path::{Path, PathBuf},
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
-/// static information about this application
-///
-/// Used to determine where to put local data on the users filesystem
-const APP_INFO: AppInfo = AppInfo {
-    name: "evry",
-    author: "seanbreckenridge",
-};
+use crate::{utils, xdg};
+
+/// name of this application, used to namespace
+/// the directories evry saves files to
+const APP_NAME: &str = "evry";
+
+/// the author older versions passed to `app_dirs`, which is part of the legacy data directory on Windows
+#[cfg(windows)]
+const LEGACY_APP_AUTHOR: &str = "seanbreckenridge";
+
+/// name of the directory which holds the tags for a single project,
+/// found by walking up from the current directory
+pub const PROJECT_DIR_NAME: &str = ".evry";
+
+/// Where the data directory was resolved from
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DirSource {
+    /// the `--dir` option, or the `EVRY_DIR` environment variable
+    Env,
+    /// a `.evry` directory in the current directory or one of its parents
+    Project,
+    /// the global, per-user tag directory, in `$XDG_STATE_HOME`
+    #[default]
+    Global,
+    /// the global data directory older versions used, in `$XDG_DATA_HOME`.
+    /// This is used until the tags are moved with `evry migrate`
+    Legacy,
+}
+
+impl DirSource {
+    /// short description, used in debug output
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DirSource::Env => "env",
+            DirSource::Project => "project",
+            DirSource::Global => "global",
+            DirSource::Legacy => "legacy",
+        }
+    }
+}
 
 /// Keeps track of the user data dir, creates directories if they don't exist
 #[derive(Debug, Default)]
 pub struct LocalDir {
     pub data_dir: PathBuf,
+    /// how `data_dir` was chosen
+    pub source: DirSource,
+}
+
+/// how long to wait for another evry process to release the data directory
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// returned when another evry process held the lock on the
+/// data directory for longer than `LOCK_TIMEOUT`
+#[derive(Debug)]
+pub struct LockError(pub PathBuf);
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Timed out waiting for another evry process to unlock {}",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// returned when another evry process is already checking a tag
+#[derive(Debug)]
+pub struct AlreadyRunning(pub String);
+
+impl Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Another evry process is already checking tag '{}'",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// An exclusive lock on the data directory, released when its dropped
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+/// An exclusive lock on a tag file, released when its dropped
+#[derive(Debug)]
+pub struct TagLock {
+    _file: File,
+}
+
+/// tries to take an exclusive lock on a file, returns false if its already locked
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the file descriptor is valid for as long as `file` is
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// locking isn't supported on this platform, so always succeed
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
 }
 
 impl LocalDir {
     /// Creates application/data directories if they don't exist
-    pub fn new() -> Result<Self, Error> {
-        let dir_info: PathBuf = app_dirs::get_app_root(AppDataType::UserData, &APP_INFO)
-            .context("Couldn't get user local data directory")?;
-        // use EVRY_DIR environment variable, if it exists
-        let evry_env = std::env::var("EVRY_DIR");
-        let evry_dir: &Path = match evry_env {
-            Ok(ref evry_environ) => Path::new(evry_environ),
-            Err(_) => dir_info.as_path(),
-        };
+    ///
+    /// Uses (in order of precedence) the directory passed with `--dir` (or the `EVRY_DIR` environment variable),
+    /// a `.evry` directory in the current directory or one of its parents,
+    /// the legacy data directory (if it has tags that haven't been migrated yet),
+    /// or the global tag directory
+    pub fn new(evry_dir: Option<String>) -> Result<Self, Error> {
+        // use --dir/EVRY_DIR, if it was set
+        if let Some(evry_dir) = evry_dir {
+            // hmm -- not really needed anymore since we don't have any other files there (rollback was
+            // removed), but will keep for backwards compatibility
+            return Self::create(Path::new(&evry_dir).join("data"), DirSource::Env);
+        }
 
-        // hmm -- not really needed anymore since we don't have any other files there (rollback was
-        // removed), but will keep for backwards compatibility
-        let data_dir = evry_dir.join("data");
+        // like git, use the closest project directory, if there is one
+        let cwd = std::env::current_dir().context("Couldn't get current directory")?;
+        if let Some(project_dir) = find_project_dir(&cwd) {
+            return Self::create(project_dir, DirSource::Project);
+        }
+
+        Self::global(global_tag_dir()?, &legacy_data_dirs()?)
+    }
+
+    /// uses the first legacy data directory which has tags that haven't
+    /// been migrated yet, otherwise the global tag directory
+    fn global(global_dir: PathBuf, legacy_dirs: &[PathBuf]) -> Result<Self, Error> {
+        if !global_dir.exists() {
+            if let Some(legacy_dir) = legacy_dirs.iter().find(|dir| has_entries(dir)) {
+                return Ok(Self {
+                    data_dir: legacy_dir.clone(),
+                    source: DirSource::Legacy,
+                });
+            }
+        }
+        Self::create(global_dir, DirSource::Global)
+    }
+
+    /// creates the data directory if it doesn't exist
+    fn create(data_dir: PathBuf, source: DirSource) -> Result<Self, Error> {
         create_dir_all(&data_dir).context("Could not create evry local directory")?;
-        Ok(Self { data_dir })
+        Ok(Self { data_dir, source })
+    }
+
+    /// Lists the names of every tag in the data directory, or
+    /// every tag in a namespace if one is given, sorted by name
+    ///
+    /// Files which can't be decoded into a tag name are skipped, see `skipped_files`
+    pub fn list_tags(&self, namespace: Option<&Tag>) -> Result<Vec<String>, Error> {
+        let mut skipped = vec![];
+        let mut names = match namespace {
+            Some(tag) if tag.path.is_file() => vec![tag.name.clone()],
+            Some(tag) if tag.is_namespace() => walk_tags(&tag.path, Some(&tag.name), &mut skipped)?,
+            Some(_) => vec![],
+            None => walk_tags(&self.data_dir, None, &mut skipped)?,
+        };
+        names.sort();
+        Ok(names)
+    }
+
+    /// Lists the files in the data directory which aren't tags, since
+    /// their names can't be decoded (e.g. they weren't created by evry)
+    pub fn skipped_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut skipped = vec![];
+        walk_tags(&self.data_dir, None, &mut skipped)?;
+        skipped.sort();
+        Ok(skipped)
+    }
+
+    /// Where `migrate_to` should move the tags from the legacy data directory:
+    /// the global tag directory, and a backup in the cache directory
+    pub fn migrate_dirs(&self) -> Result<(PathBuf, PathBuf), Error> {
+        if self.source != DirSource::Legacy {
+            bail!(
+                "Nothing to migrate, {} isn't the legacy data directory",
+                self.data_dir.display()
+            );
+        }
+        let global_dir = global_tag_dir()?;
+        let backup_dir = xdg::cache_home()?.join(APP_NAME).join(format!(
+            "legacy-data-{}",
+            utils::epoch_millis().context("Couldn't get current time")?
+        ));
+        Ok((global_dir, backup_dir))
+    }
+
+    /// copies the tags to `global_dir` and `backup_dir`, and then removes the data directory.
+    /// Tags which older versions saved without encoding their names are renamed in `global_dir`
+    pub fn migrate_to(&self, global_dir: &Path, backup_dir: &Path) -> Result<(), Error> {
+        copy_tree(&self.data_dir, global_dir, true)?;
+        copy_tree(&self.data_dir, backup_dir, false)?;
+        remove_dir_all(&self.data_dir).context(format!(
+            "Could not remove legacy data directory {}",
+            self.data_dir.display()
+        ))
+    }
+
+    /// Locks the data directory, so that two evry processes checking the
+    /// same tag at the same time don't both decide that it should run.
+    ///
+    /// The lock is only held while a tag is being checked, so this waits
+    /// up to `LOCK_TIMEOUT` for any other process to finish
+    pub fn lock(&self) -> Result<DirLock, Error> {
+        let dir = File::open(&self.data_dir).context(format!(
+            "Could not open data directory {}",
+            self.data_dir.display()
+        ))?;
+        let started = Instant::now();
+        while !try_lock(&dir).context(format!("Could not lock {}", self.data_dir.display()))? {
+            if started.elapsed() > LOCK_TIMEOUT {
+                bail!(LockError(self.data_dir.clone()));
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Ok(DirLock { _file: dir })
+    }
+}
+
+/// where tags are saved by default, `$XDG_STATE_HOME/evry/tags`
+fn global_tag_dir() -> Result<PathBuf, Error> {
+    Ok(xdg::state_home()?.join(APP_NAME).join("tags"))
+}
+
+/// where older versions saved tags: the `data` folder in the per-platform data
+/// directory the `app_dirs` crate used, and `$XDG_DATA_HOME/evry/data`
+fn legacy_data_dirs() -> Result<Vec<PathBuf>, Error> {
+    let mut dirs = vec![legacy_app_root()?.join("data")];
+    let xdg_dir = xdg::data_home()?.join(APP_NAME).join("data");
+    if !dirs.contains(&xdg_dir) {
+        dirs.push(xdg_dir);
+    }
+    Ok(dirs)
+}
+
+/// the directory `app_dirs` saved user data to on macOS, `~/Library/Application Support/evry`
+#[cfg(target_os = "macos")]
+fn legacy_app_root() -> Result<PathBuf, Error> {
+    Ok(xdg::home()?
+        .join("Library")
+        .join("Application Support")
+        .join(APP_NAME))
+}
+
+/// the directory `app_dirs` saved user data to on Windows, `%APPDATA%\seanbreckenridge\evry`
+#[cfg(windows)]
+fn legacy_app_root() -> Result<PathBuf, Error> {
+    let app_data = std::env::var_os("APPDATA")
+        .context("Couldn't determine the legacy data directory, APPDATA isn't set")?;
+    Ok(PathBuf::from(app_data)
+        .join(LEGACY_APP_AUTHOR)
+        .join(APP_NAME))
+}
+
+/// the directory `app_dirs` saved user data to elsewhere, `$XDG_DATA_HOME/evry`
+#[cfg(not(any(target_os = "macos", windows)))]
+fn legacy_app_root() -> Result<PathBuf, Error> {
+    Ok(xdg::data_home()?.join(APP_NAME))
+}
+
+/// whether or not `dir` is a directory with anything in it
+fn has_entries(dir: &Path) -> bool {
+    read_dir(dir).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// recursively copies the files from one directory to another
+///
+/// With `encode_names`, files named by older versions of evry (which didn't encode tag
+/// names) are renamed to the filename `encode_tag_name` gives their tag name
+fn copy_tree(from: &Path, to: &Path, encode_names: bool) -> Result<(), Error> {
+    create_dir_all(to).context(format!("Could not create directory {}", to.display()))?;
+    for entry in read_dir(from).context(format!("Could not read directory {}", from.display()))? {
+        let entry = entry.context("Could not read directory entry")?;
+        let filename = entry.file_name();
+        let target = match filename.to_str() {
+            Some(name) if encode_names && !is_encoded(name) && legacy_name(name) => {
+                to.join(encode_tag_name(name))
+            }
+            _ => to.join(&filename),
+        };
+        if entry.path().is_dir() {
+            copy_tree(&entry.path(), &target, encode_names)?;
+        } else {
+            copy(entry.path(), &target).context(format!(
+                "Could not copy {} to {}",
+                entry.path().display(),
+                target.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// walks up from `start`, returning the first `.evry` directory found
+pub fn find_project_dir(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_DIR_NAME))
+        .find(|dir| dir.is_dir())
+}
+
+/// recursively collects the (decoded) tag names in a directory, adding
+/// any files (or directories) which can't be decoded into a tag name to `skipped`.
+/// Files saved by older versions of evry, which didn't encode tag names, are
+/// listed under their filename
+fn walk_tags(
+    dir: &Path,
+    prefix: Option<&str>,
+    skipped: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    for entry in read_dir(dir).context(format!("Could not read directory {}", dir.display()))? {
+        let entry = entry.context("Could not read directory entry")?;
+        let part = match entry.file_name().to_str() {
+            Some(filename) if is_encoded(filename) => decode_tag_name(filename)?,
+            Some(filename) if legacy_name(filename) => filename.to_string(),
+            _ => {
+                skipped.push(entry.path());
+                continue;
+            }
+        };
+        let name = match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, NAMESPACE_SEPARATOR, part),
+            None => part,
+        };
+        if entry.path().is_dir() {
+            names.extend(walk_tags(&entry.path(), Some(&name), skipped)?);
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// metadata key for the interval (milliseconds) the tag was last run with
+pub const INTERVAL_KEY: &str = "interval";
+/// metadata key for the exit code of the last run, saved by `evry outcome`
+pub const EXIT_CODE_KEY: &str = "exit_code";
+/// metadata key for when the last run finished (epoch milliseconds), saved by `evry outcome`
+pub const EXITED_AT_KEY: &str = "exited_at";
+/// metadata key for when the last run which exited successfully finished
+/// (epoch milliseconds), saved by `evry outcome`
+pub const LAST_SUCCESS_KEY: &str = "last_success";
+/// metadata key for the boot ID when the tag last ran, saved with --monotonic
+pub const BOOT_ID_KEY: &str = "boot_id";
+/// metadata key for CLOCK_BOOTTIME (milliseconds) when the tag last ran, saved with --monotonic
+pub const BOOTTIME_KEY: &str = "boottime";
+/// metadata key for the boot ID when the active time was last updated, saved with --active
+pub const ACTIVE_BOOT_ID_KEY: &str = "active_boot_id";
+/// metadata key for CLOCK_MONOTONIC (milliseconds) when the active time was last updated
+pub const ACTIVE_CLOCK_KEY: &str = "active_clock";
+/// metadata key for how long (milliseconds) the machine has been running
+/// (not suspended) since the tag last ran, as of `ACTIVE_CLOCK_KEY`
+pub const ACTIVE_KEY: &str = "active";
+
+/// Everything saved in a tag file
+///
+/// The first line is when the tag last ran (epoch milliseconds), which is
+/// all older versions of evry saved. Any lines after that are `key=value` metadata
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TagState {
+    /// when the tag last ran, as epoch milliseconds
+    pub last_run: u128,
+    /// other information about the tag, e.g. the exit code of the last run
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl TagState {
+    /// parses the contents of a tag file
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let mut lines = contents.lines();
+        let first_line = lines.next().unwrap_or_default();
+        let last_run = first_line.trim().parse::<u128>().context(format!(
+            "Could not convert tag file contents '{}' to integer",
+            first_line
+        ))?;
+        let mut metadata = BTreeMap::new();
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .context(format!("Could not parse tag file metadata '{}'", line))?;
+            metadata.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        Ok(Self { last_run, metadata })
+    }
+
+    /// converts this back into the contents of a tag file
+    pub fn serialize(&self) -> String {
+        let mut contents = self.last_run.to_string();
+        for (key, value) in &self.metadata {
+            contents.push_str(&format!("\n{}={}", key, value));
+        }
+        contents
+    }
+
+    /// gets some metadata, `None` if its missing or can't be parsed
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.metadata.get(key).and_then(|value| value.parse().ok())
+    }
+
+    /// sets some metadata
+    pub fn set(&mut self, key: &str, value: impl Display) {
+        self.metadata.insert(key.to_string(), value.to_string());
+    }
+
+    /// reads a clock saved with `set_clock`
+    fn clock(&self, id_key: &str, millis_key: &str) -> Option<utils::BootTime> {
+        Some(utils::BootTime {
+            boot_id: self.get(id_key)?,
+            millis: self.get(millis_key)?,
+        })
+    }
+
+    /// saves (or removes, so a stale reading isn't compared against) a clock reading
+    fn set_clock(&mut self, id_key: &str, millis_key: &str, clock: Option<&utils::BootTime>) {
+        match clock {
+            Some(clock) => {
+                self.set(id_key, &clock.boot_id);
+                self.set(millis_key, clock.millis);
+            }
+            None => {
+                self.metadata.remove(id_key);
+                self.metadata.remove(millis_key);
+            }
+        }
+    }
+
+    /// the boot ID and CLOCK_BOOTTIME when the tag last ran, if they were saved
+    pub fn boot_time(&self) -> Option<utils::BootTime> {
+        self.clock(BOOT_ID_KEY, BOOTTIME_KEY)
     }
+
+    /// saves (or removes) the boot ID and CLOCK_BOOTTIME
+    pub fn set_boot_time(&mut self, boot: Option<&utils::BootTime>) {
+        self.set_clock(BOOT_ID_KEY, BOOTTIME_KEY, boot);
+    }
+
+    /// the boot ID and CLOCK_MONOTONIC when the active time was last updated
+    pub fn active_clock(&self) -> Option<utils::BootTime> {
+        self.clock(ACTIVE_BOOT_ID_KEY, ACTIVE_CLOCK_KEY)
+    }
+
+    /// saves how long the machine has been active since the tag last ran,
+    /// as of some CLOCK_MONOTONIC reading (or removes it, if there's no reading)
+    pub fn set_active(&mut self, clock: Option<&utils::BootTime>, active_ms: u128) {
+        self.set_clock(ACTIVE_BOOT_ID_KEY, ACTIVE_CLOCK_KEY, clock);
+        match clock {
+            Some(_) => self.set(ACTIVE_KEY, active_ms),
+            None => {
+                self.metadata.remove(ACTIVE_KEY);
+            }
+        }
+    }
+
+    /// saves the clocks read when the tag ran, resetting the active time
+    pub fn set_clocks(&mut self, clocks: &utils::Clocks) {
+        self.set_boot_time(clocks.boot.as_ref());
+        self.set_active(clocks.active.as_ref(), 0);
+    }
+
+    /// how long (milliseconds) the machine has been active since the tag last ran
+    ///
+    /// If it rebooted since the active time was last updated, the time since this
+    /// boot is added, since the time between the last update and shutting down is
+    /// unknown. If the tag hasn't run with --active before, starts from the wall time
+    pub fn active_elapsed(&self, now: u128, current: &utils::BootTime) -> u128 {
+        match (self.active_clock(), self.get::<u128>(ACTIVE_KEY)) {
+            (Some(saved), Some(active)) => active + current.since(&saved).unwrap_or(current.millis),
+            _ => now.saturating_sub(self.last_run),
+        }
+    }
+}
+
+/// read a tag file
+pub fn read_state(filepath: &Path) -> Result<TagState, Error> {
+    let contents = read_to_string(filepath).context("Could not read tag information from file")?;
+    TagState::parse(&contents).context(format!("Could not parse tag file '{}'", filepath.display()))
+}
+
+/// the longest tag name (in characters) a user can pass
+pub const MAX_TAG_LENGTH: usize = 128;
+
+/// most filesystems don't allow filenames longer than this (in bytes)
+const MAX_FILENAME_BYTES: usize = 255;
+
+/// separates the namespaces in a tag name, e.g. `backup/photos`
+///
+/// Each namespace is saved as a subdirectory of the data directory
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// characters which are escaped when converting a tag name to a filename
+const ESCAPED_CHARS: &[char] = &['%', '/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// makes sure a user-supplied tag name is something we can save to disk
+///
+/// Any printable characters are allowed (they're encoded by `encode_tag_name` if
+/// they can't be used in a filename), but each namespace has to contain something
+/// other than whitespace, and control characters (like newlines) aren't allowed
+pub fn validate_tag_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        bail!("Tag name is empty, pass a tag like '-backup'");
+    }
+    let length = name.chars().count();
+    if length > MAX_TAG_LENGTH {
+        bail!(
+            "Tag name '{}' is {} characters long, the maximum is {}",
+            name,
+            length,
+            MAX_TAG_LENGTH
+        );
+    }
+    if let Some(c) = name.chars().find(|c| c.is_control()) {
+        bail!(
+            "Tag name '{}' contains a control character ({:?})",
+            name.escape_debug(),
+            c
+        );
+    }
+    for part in name.split(NAMESPACE_SEPARATOR) {
+        if part.is_empty() {
+            bail!("Tag name '{}' has an empty namespace", name);
+        }
+        if part.trim().is_empty() {
+            bail!(
+                "Tag name '{}' has a namespace which is only whitespace",
+                name
+            );
+        }
+    }
+    Ok(())
 }
 
-/// read epoch time from a tag file
-pub fn read_epoch_millis(filepath: &str) -> Result<u128, Error> {
-    let millis_str =
-        read_to_string(filepath).context("Could not read tag information from file")?;
-    millis_str.trim().parse::<u128>().context(format!(
-        "Could not convert tag file contents '{}' to integer for tag '{}'",
-        millis_str, filepath
-    ))
+/// converts one part of a tag name to a filename that can't escape the data directory
+///
+/// Anything that could be interpreted as a path (separators, a leading '.')
+/// or isn't allowed on common filesystems is percent-encoded, so the
+/// original name can be recovered with `decode_tag_name`
+///
+/// Converts `..` to `%2E.` and `a:b` to `a%3Ab`
+pub fn encode_tag_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        if ESCAPED_CHARS.contains(&c) || (i == 0 && c == '.') {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+/// converts a filename created by `encode_tag_name` back into a tag name
+pub fn decode_tag_name(filename: &str) -> Result<String, Error> {
+    let bytes = filename.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = filename
+                .get(i + 1..i + 3)
+                .context(format!("Truncated escape sequence in '{}'", filename))?;
+            // from_str_radix also accepts a sign, like `+F`
+            if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                bail!("Invalid escape sequence in '{}'", filename);
+            }
+            decoded.push(
+                u8::from_str_radix(hex, 16)
+                    .context(format!("Invalid escape sequence in '{}'", filename))?,
+            );
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).context(format!("'{}' doesn't decode to UTF-8", filename))
+}
+
+/// whether a filename is exactly what `encode_tag_name` gives for some valid tag name
+fn is_encoded(filename: &str) -> bool {
+    matches!(decode_tag_name(filename),
+        Ok(name) if encode_tag_name(&name) == filename && validate_tag_name(&name).is_ok())
+}
+
+/// whether a filename which isn't encoded could be a tag saved by an older version
+/// of evry, which used the tag name as the filename
+fn legacy_name(filename: &str) -> bool {
+    filename != "." && filename != ".." && validate_tag_name(filename).is_ok()
+}
+
+/// the number of single-character insertions, deletions or substitutions to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// finds the closest name to a (probably misspelled) tag name, if any are close enough
+///
+/// A name is close enough if it's within one edit for every 3 characters
+pub fn suggest_tag_name<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .filter(|candidate| candidate.as_str() != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate.as_str())
 }
 
 /// A 'tag' is the name of some evry task
@@ -66,42 +665,483 @@ pub fn read_epoch_millis(filepath: &str) -> Result<u128, Error> {
 /// ```
 /// evry 2 months -sometool && run tool
 /// evry 10 minutes -requestfile && wget ...
+/// evry 1 week -backup/photos && rsync ...
 /// ```
+///
+/// A tag can be put in a namespace (`backup/photos`), which is saved
+/// as a subdirectory of the data directory
 #[derive(Debug)]
 pub struct Tag {
     /// the name of this tag, like `requestfile`
     pub name: String,
-    /// the computed location of this tag, like `~/.local/share/evry/data/requestfile`
-    pub path: String,
+    /// the computed location of this tag, like `~/.local/state/evry/tags/requestfile`
+    pub path: PathBuf,
 }
 
 impl Tag {
-    /// Creates a new tag, resolves its `path`
-    pub fn new(name: String, local_dir: &LocalDir) -> Self {
-        let mut buf = local_dir.data_dir.clone();
-        buf.push(&name);
-        // man, this is ugly
-        let path = buf
-            .into_os_string()
-            .into_string()
-            .expect("Could not convert path to string");
-        Self { name, path }
+    /// Creates a new tag, validates the name and resolves its `path`
+    ///
+    /// Older versions of evry saved tags under their name without encoding it, so if
+    /// the encoded file doesn't exist but one named like that does, that's used instead
+    pub fn new(name: String, local_dir: &LocalDir) -> Result<Self, Error> {
+        validate_tag_name(&name)?;
+        let mut path = local_dir.data_dir.clone();
+        let mut legacy_path = Some(local_dir.data_dir.clone());
+        for part in name.split(NAMESPACE_SEPARATOR) {
+            let filename = encode_tag_name(part);
+            // a filename which is the encoding of a different name belongs to that tag
+            legacy_path = legacy_path
+                .filter(|_| (filename == part || !is_encoded(part)) && legacy_name(part))
+                .map(|legacy| legacy.join(part));
+            if filename.len() > MAX_FILENAME_BYTES {
+                bail!(
+                    "Part of tag name '{}' is too long once escaped ({} bytes, the maximum is {})",
+                    name,
+                    filename.len(),
+                    MAX_FILENAME_BYTES
+                );
+            }
+            path.push(filename);
+        }
+        let path = match legacy_path {
+            Some(legacy) if legacy != path && !path.exists() && legacy.exists() => legacy,
+            _ => path,
+        };
+        Ok(Self { name, path })
+    }
+
+    /// Returns whether or not this is a namespace which contains other tags
+    pub fn is_namespace(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Makes sure this tag can be saved, which it can't be if one of its namespaces is
+    /// already a tag (`a`, when saving `a/b`), or if its already a namespace (`a/b`, when saving `a`)
+    pub fn check_clash(&self) -> Result<(), Error> {
+        let parts: Vec<&str> = self.name.split(NAMESPACE_SEPARATOR).collect();
+        // the parent directories, starting with the innermost namespace
+        for (depth, dir) in self
+            .path
+            .ancestors()
+            .skip(1)
+            .take(parts.len() - 1)
+            .enumerate()
+        {
+            if dir.is_file() {
+                bail!(
+                    "Can't save tag '{}', '{}' is a tag so it can't be used as a namespace",
+                    self.name,
+                    parts[..parts.len() - 1 - depth].join(&NAMESPACE_SEPARATOR.to_string())
+                );
+            }
+        }
+        if self.is_namespace() {
+            let inner = walk_tags(&self.path, Some(&self.name), &mut vec![])?;
+            match inner.iter().min() {
+                Some(inner) => bail!(
+                    "Can't save tag '{}', its a namespace which contains other tags (like '{}')",
+                    self.name,
+                    inner
+                ),
+                None => bail!(
+                    "Can't save tag '{}', its a namespace directory ({})",
+                    self.name,
+                    self.path.display()
+                ),
+            }
+        }
+        Ok(())
     }
 
     /// Returns whether or not the corresponding tag file exists
     pub fn file_exists(&self) -> bool {
-        Path::new(&self.path).exists()
+        self.path.exists()
+    }
+
+    /// Reads everything saved in the tag file
+    pub fn read_state(&self) -> Result<TagState, Error> {
+        read_state(&self.path)
     }
 
-    /// Reads from the tag file, returning when this tag was last run
-    pub fn read_epoch_millis(&self) -> Result<u128, Error> {
-        read_epoch_millis(&self.path)
+    /// Writes a number (epoch datetime) to this tagfile, along with the
+    /// interval (milliseconds) it was run with and the boot time, keeping any other metadata.
+    ///
+    /// If the existing tag file can't be read this fails, instead of discarding its metadata
+    pub fn write(&self, time: u128, interval: u128, clocks: &utils::Clocks) -> Result<(), Error> {
+        let mut state = match self.file_exists() {
+            true => self.read_state()?,
+            false => TagState::default(),
+        };
+        state.last_run = time;
+        state.set(INTERVAL_KEY, interval);
+        state.set_clocks(clocks);
+        self.write_state(&state)
+    }
+
+    /// Locks this tag file, so that a second evry process checking the same tag
+    /// exits with `AlreadyRunning` instead of waiting for the data directory lock.
+    ///
+    /// A tag which doesn't exist yet can't be locked, so `None` is returned
+    pub fn lock(&self) -> Result<Option<TagLock>, Error> {
+        if !self.file_exists() {
+            return Ok(None);
+        }
+        let file = File::open(&self.path).context("Could not open tag file")?;
+        if !try_lock(&file).context(format!("Could not lock tag file for '{}'", self.name))? {
+            bail!(AlreadyRunning(self.name.clone()));
+        }
+        Ok(Some(TagLock { _file: file }))
     }
 
-    /// Writes a number (epoch datetime) to this tagfile
-    pub fn write(&self, time: u128) -> Result<(), Error> {
+    /// Writes the tag state to this tagfile
+    pub fn write_state(&self, state: &TagState) -> Result<(), Error> {
+        self.check_clash()?;
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).context(format!(
+                "Could not create namespace directory for tag '{}'",
+                self.name
+            ))?;
+        }
         let fp = File::create(&self.path).context("Could not create tag file")?;
         let mut writer = BufWriter::new(&fp);
-        write!(&mut writer, "{}", time).context("Could not write to file")
+        write!(&mut writer, "{}", state.serialize()).context("Could not write to file")
+    }
+
+    /// Removes this tag file, or every tag in this namespace.
+    /// Returns the names of the tags which were removed
+    pub fn remove(&self, local_dir: &LocalDir) -> Result<Vec<String>, Error> {
+        let removed = local_dir.list_tags(Some(self))?;
+        if self.is_namespace() {
+            remove_dir_all(&self.path).context(format!(
+                "Could not remove namespace directory {}",
+                self.path.display()
+            ))?;
+        } else if self.file_exists() {
+            remove_file(&self.path)
+                .context(format!("Could not remove tag file {}", self.path.display()))?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_tag_name() {
+        assert_eq!(encode_tag_name("scrapesite"), "scrapesite");
+        assert_eq!(encode_tag_name(".."), "%2E.");
+        assert_eq!(encode_tag_name("../../foo"), "%2E.%2F..%2Ffoo");
+        assert_eq!(encode_tag_name(".hidden"), "%2Ehidden");
+        assert_eq!(encode_tag_name("a.b:c"), "a.b%3Ac");
+        assert_eq!(encode_tag_name("100%"), "100%25");
+    }
+
+    #[test]
+    fn test_suggest_tag_name() {
+        assert_eq!(edit_distance("scrapsite", "scrapesite"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        let tags = vec![
+            "backup/photos".to_string(),
+            "scrapesite".to_string(),
+            "scrapesite2".to_string(),
+        ];
+        assert_eq!(suggest_tag_name("scrapsite", &tags), Some("scrapesite"));
+        assert_eq!(
+            suggest_tag_name("backup/phtos", &tags),
+            Some("backup/photos")
+        );
+        assert_eq!(suggest_tag_name("mail", &tags), None);
+    }
+
+    #[test]
+    fn test_decode_roundtrip() {
+        for name in ["backup", "../../etc/passwd", ".x", "a%2Fb", "ünïcödé/\\"] {
+            assert_eq!(decode_tag_name(&encode_tag_name(name)).unwrap(), name);
+        }
+        assert!(decode_tag_name("bad%2").is_err());
+        assert!(decode_tag_name("bad%zz").is_err());
+        assert!(decode_tag_name("bad%+F").is_err());
+        assert!(decode_tag_name("bad%-1").is_err());
+        assert!(is_encoded("100%25"));
+        assert!(!is_encoded("100%"));
+        assert!(!is_encoded("a:b"));
+        // encode_tag_name uses uppercase hex digits
+        assert!(!is_encoded("%2ehidden"));
+    }
+
+    #[test]
+    fn test_legacy_tag_path() {
+        let data_dir =
+            std::env::temp_dir().join(format!("evry-test-legacy-path-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        create_dir_all(&data_dir).unwrap();
+        let tag = Tag::new("a:b".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.path, data_dir.join("a%3Ab"));
+        // an older version saved the tag without encoding its name
+        std::fs::write(data_dir.join("a:b"), "1697592310021").unwrap();
+        let tag = Tag::new("a:b".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.path, data_dir.join("a:b"));
+        assert_eq!(tag.read_state().unwrap().last_run, 1697592310021);
+        // the encoded file is used if both exist
+        std::fs::write(data_dir.join("a%3Ab"), "1697592310022").unwrap();
+        let tag = Tag::new("a:b".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.read_state().unwrap().last_run, 1697592310022);
+        // a filename which is encoded isn't the tag name itself
+        std::fs::write(data_dir.join("100%25"), "1").unwrap();
+        let tag = Tag::new("100%25".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.path, data_dir.join("100%2525"));
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_namespace_path() {
+        let local_dir = LocalDir {
+            data_dir: PathBuf::from("/data"),
+            ..Default::default()
+        };
+        let tag = Tag::new("backup/photos".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.path, PathBuf::from("/data/backup/photos"));
+        let tag = Tag::new("../../etc".to_string(), &local_dir).unwrap();
+        assert_eq!(tag.path, PathBuf::from("/data/%2E./%2E./etc"));
+        assert!(Tag::new("backup//photos".to_string(), &local_dir).is_err());
+        assert!(Tag::new("backup/".to_string(), &local_dir).is_err());
+    }
+
+    #[test]
+    fn test_list_tags() {
+        let data_dir = std::env::temp_dir().join(format!("evry-test-list-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        for name in ["backup/photos", "scrapesite"] {
+            Tag::new(name.to_string(), &local_dir)
+                .unwrap()
+                .write_state(&TagState::default())
+                .unwrap();
+        }
+        // saved by older versions, which didn't encode tag names
+        File::create(data_dir.join("100%")).unwrap();
+        File::create(data_dir.join("backup").join("a:b")).unwrap();
+        // not created by evry, so they can't be tag names
+        File::create(data_dir.join("new\nline")).unwrap();
+        create_dir_all(data_dir.join("backup").join(" ")).unwrap();
+        assert_eq!(
+            local_dir.list_tags(None).unwrap(),
+            vec!["100%", "backup/a:b", "backup/photos", "scrapesite"]
+        );
+        assert_eq!(
+            local_dir.skipped_files().unwrap(),
+            vec![
+                data_dir.join("backup").join(" "),
+                data_dir.join("new\nline")
+            ]
+        );
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_tag_lock() {
+        let data_dir =
+            std::env::temp_dir().join(format!("evry-test-tag-lock-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        let tag = Tag::new("backup".to_string(), &local_dir).unwrap();
+        // a new tag can't be locked
+        assert!(tag.lock().unwrap().is_none());
+        tag.write_state(&TagState::default()).unwrap();
+        let lock = tag.lock().unwrap();
+        assert!(lock.is_some());
+        // flock locks belong to the open file, so this conflicts in the same process
+        let err = tag.lock().unwrap_err();
+        assert!(err.is::<AlreadyRunning>());
+        // other tags aren't locked
+        let other = Tag::new("other".to_string(), &local_dir).unwrap();
+        other.write_state(&TagState::default()).unwrap();
+        assert!(other.lock().unwrap().is_some());
+        drop(lock);
+        assert!(tag.lock().unwrap().is_some());
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_clash() {
+        let data_dir = std::env::temp_dir().join(format!("evry-test-clash-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        let tag = |name: &str| Tag::new(name.to_string(), &local_dir).unwrap();
+        tag("a").write_state(&TagState::default()).unwrap();
+        tag("c/d/e").write_state(&TagState::default()).unwrap();
+        let err = tag("a/b/c").write_state(&TagState::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't save tag 'a/b/c', 'a' is a tag so it can't be used as a namespace"
+        );
+        let err = tag("c").write_state(&TagState::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't save tag 'c', its a namespace which contains other tags (like 'c/d/e')"
+        );
+        assert!(tag("c/d").check_clash().is_err());
+        assert!(tag("c/f").check_clash().is_ok());
+        assert!(tag("a").check_clash().is_ok());
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let root = std::env::temp_dir().join(format!("evry-test-migrate-{}", std::process::id()));
+        let global_dir = root.join("state").join("tags");
+        let legacy_dir = root.join("data").join("data");
+        let backup_dir = root.join("cache").join("legacy-data");
+        // the app_dirs directory is checked first, it doesn't have any tags here
+        let legacy_dirs = [
+            root.join("app-data").join("evry").join("data"),
+            legacy_dir.clone(),
+        ];
+
+        // nothing to migrate, so the global directory is created
+        let local_dir = LocalDir::global(global_dir.clone(), &legacy_dirs).unwrap();
+        assert_eq!(local_dir.source, DirSource::Global);
+        assert!(global_dir.is_dir());
+        assert!(local_dir.migrate_dirs().is_err());
+        remove_dir_all(&global_dir).unwrap();
+
+        let legacy = LocalDir {
+            data_dir: legacy_dir.clone(),
+            source: DirSource::Legacy,
+        };
+        for name in ["scrapesite", "backup/photos"] {
+            Tag::new(name.to_string(), &legacy)
+                .unwrap()
+                .write_state(&TagState {
+                    last_run: 1697592310021,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        // saved by a version which didn't encode tag names
+        std::fs::write(legacy_dir.join("backup").join("a:b"), "1697592310021").unwrap();
+        let local_dir = LocalDir::global(global_dir.clone(), &legacy_dirs).unwrap();
+        assert_eq!(local_dir.source, DirSource::Legacy);
+        assert_eq!(local_dir.data_dir, legacy_dir);
+        assert!(!global_dir.exists());
+
+        local_dir.migrate_to(&global_dir, &backup_dir).unwrap();
+        assert!(!legacy_dir.exists());
+        for dir in [&global_dir, &backup_dir] {
+            let migrated = LocalDir {
+                data_dir: dir.clone(),
+                ..Default::default()
+            };
+            assert_eq!(
+                migrated.list_tags(None).unwrap(),
+                vec!["backup/a:b", "backup/photos", "scrapesite"]
+            );
+            for name in ["backup/photos", "backup/a:b"] {
+                let tag = Tag::new(name.to_string(), &migrated).unwrap();
+                assert_eq!(tag.read_state().unwrap().last_run, 1697592310021);
+            }
+        }
+        // renamed in the global directory, the backup is left as it was
+        assert!(global_dir.join("backup").join("a%3Ab").is_file());
+        assert!(backup_dir.join("backup").join("a:b").is_file());
+        // once the global directory exists, the legacy directory isn't used
+        create_dir_all(&legacy_dir).unwrap();
+        File::create(legacy_dir.join("old")).unwrap();
+        let local_dir = LocalDir::global(global_dir.clone(), &legacy_dirs).unwrap();
+        assert_eq!(local_dir.source, DirSource::Global);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_find_project_dir() {
+        let root = std::env::temp_dir().join(format!("evry-test-project-{}", std::process::id()));
+        let nested = root.join("src").join("nested");
+        create_dir_all(&nested).unwrap();
+        assert_ne!(find_project_dir(&nested), Some(root.join(PROJECT_DIR_NAME)));
+        create_dir_all(root.join(PROJECT_DIR_NAME)).unwrap();
+        assert_eq!(find_project_dir(&nested), Some(root.join(PROJECT_DIR_NAME)));
+        assert_eq!(find_project_dir(&root), Some(root.join(PROJECT_DIR_NAME)));
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_tag_state() {
+        // older versions only saved the timestamp
+        let state = TagState::parse("1697592310021").unwrap();
+        assert_eq!(state.last_run, 1697592310021);
+        assert!(state.metadata.is_empty());
+        assert_eq!(state.serialize(), "1697592310021");
+
+        let mut state = TagState::parse("1697592310021\nexit_code=2\n").unwrap();
+        assert_eq!(state.get::<i32>(EXIT_CODE_KEY), Some(2));
+        assert_eq!(state.get::<u128>(EXITED_AT_KEY), None);
+        state.set(EXITED_AT_KEY, 1697592315021u128);
+        assert_eq!(
+            state.serialize(),
+            "1697592310021\nexit_code=2\nexited_at=1697592315021"
+        );
+        assert_eq!(TagState::parse(&state.serialize()).unwrap(), state);
+
+        let boot = utils::BootTime {
+            boot_id: "0b4e5bb8-5d9b-4a31-a4d5-4fdd5c5f8e0e".to_string(),
+            millis: 5_000,
+        };
+        state.set_boot_time(Some(&boot));
+        assert_eq!(
+            TagState::parse(&state.serialize()).unwrap().boot_time(),
+            Some(boot)
+        );
+        state.set_boot_time(None);
+        assert_eq!(state.boot_time(), None);
+        assert!(!state.metadata.contains_key(BOOT_ID_KEY));
+
+        assert!(TagState::parse("").is_err());
+        assert!(TagState::parse("abc").is_err());
+        assert!(TagState::parse("100\nno separator").is_err());
+    }
+
+    #[test]
+    fn test_active_elapsed() {
+        let clock = |boot_id: &str, millis| utils::BootTime {
+            boot_id: boot_id.to_string(),
+            millis,
+        };
+        let mut state = TagState {
+            last_run: 1_000,
+            ..Default::default()
+        };
+        // hasn't run with --active, so uses the wall time
+        assert_eq!(state.active_elapsed(11_000, &clock("a", 50_000)), 10_000);
+        state.set_active(Some(&clock("a", 50_000)), 3_000);
+        assert_eq!(state.active_elapsed(0, &clock("a", 54_000)), 7_000);
+        // rebooted, so the time since this boot is added
+        assert_eq!(state.active_elapsed(0, &clock("b", 2_000)), 5_000);
+        state.set_active(None, 0);
+        assert!(state.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_validate_tag_name() {
+        assert!(validate_tag_name("scrapesite").is_ok());
+        assert!(validate_tag_name("").is_err());
+        assert!(validate_tag_name("new\nline").is_err());
+        assert!(validate_tag_name(&"a".repeat(MAX_TAG_LENGTH)).is_ok());
+        assert!(validate_tag_name(&"a".repeat(MAX_TAG_LENGTH + 1)).is_err());
+        assert!(validate_tag_name(" padded ").is_ok());
+        assert!(validate_tag_name("   ").is_err());
+        assert!(validate_tag_name("backup/ /photos").is_err());
+        assert!(validate_tag_name("backup//photos").is_err());
+        assert!(validate_tag_name("backup/").is_err());
     }
 }
@@ -0,0 +1,653 @@
+//! Converts crontab and anacrontab entries into a job file, used by `evry import`
+//!
+//! Schedules are converted to how often they'd run, along with the time of day
+//! and days of the week they're pinned to (like `1 day on mon,tue at 09:00`), and
+//! anything that can't be represented that way is skipped with a warning
+
+use anyhow::{Error, Result};
+
+use crate::{
+    file,
+    jobs::{self, Job},
+    parser,
+    schedule::{Weekdays, DAY_NAMES},
+};
+
+/// A job converted from a line in a crontab/anacrontab
+#[derive(Debug, PartialEq)]
+pub struct ImportedJob {
+    pub job: Job,
+    /// the line the job was converted from, saved as a comment above the job
+    pub source: String,
+}
+
+/// The result of converting a crontab/anacrontab
+#[derive(Debug, Default, PartialEq)]
+pub struct Import {
+    pub jobs: Vec<ImportedJob>,
+    /// entries which were skipped, or couldn't be converted exactly
+    pub warnings: Vec<String>,
+}
+
+impl Import {
+    fn warn(&mut self, lineno: usize, message: String) {
+        self.warnings.push(format!("line {}: {}", lineno, message));
+    }
+
+    /// adds a job, unless the duration doesn't parse or the tag is invalid/already used
+    fn add(&mut self, lineno: usize, tag: String, every: &str, command: String, source: &str) {
+        if let Err(e) = file::validate_tag_name(&tag) {
+            return self.warn(lineno, format!("skipping, {}", e));
+        }
+        if self.jobs.iter().any(|imported| imported.job.tag == tag) {
+            return self.warn(lineno, format!("skipping, job '{}' already exists", tag));
+        }
+        let schedule = match parser::parse_schedule(every) {
+            Ok(schedule) => schedule,
+            Err(_) => {
+                return self.warn(
+                    lineno,
+                    format!("skipping, couldn't parse '{}' into a duration", every),
+                )
+            }
+        };
+        self.jobs.push(ImportedJob {
+            job: Job {
+                tag,
+                every: every.to_string(),
+                schedule,
+                command: Some(command),
+            },
+            source: source.to_string(),
+        });
+    }
+
+    /// renders the jobs as a job file
+    pub fn render(&self, format: &str) -> String {
+        self.jobs
+            .iter()
+            .map(|imported| {
+                format!(
+                    "# from {}: {}\n{}",
+                    format,
+                    imported.source,
+                    jobs::format_job(&imported.job)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// splits the first `n` whitespace separated fields off a line, returning
+/// them and the rest of the line (with its whitespace intact)
+fn split_fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut fields = vec![];
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    match rest.trim_end() {
+        "" => None,
+        rest => Some((fields, rest)),
+    }
+}
+
+/// whether a line sets an environment variable, like `SHELL=/bin/sh` or `MAILTO = root`
+fn env_assignment(line: &str) -> Option<&str> {
+    let (name, _) = line.split_once('=')?;
+    let name = name.trim();
+    match !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        true => Some(name),
+        false => None,
+    }
+}
+
+/// converts an anacron period (days, or a `@period` name) to a duration
+fn anacron_period(period: &str) -> Option<String> {
+    match period {
+        "@daily" => Some("1 day".to_string()),
+        "@weekly" => Some("1 week".to_string()),
+        "@monthly" => Some("1 month".to_string()),
+        "@yearly" | "@annually" => Some("1 year".to_string()),
+        days => match days.parse::<u64>() {
+            Ok(0) | Err(_) => None,
+            Ok(1) => Some("1 day".to_string()),
+            Ok(days) => Some(format!("{} days", days)),
+        },
+    }
+}
+
+/// parses an anacrontab, where each job is `period delay job-identifier command`
+///
+/// The job identifier is used as the tag name. anacron waits for the delay (in
+/// minutes) before it runs a job, so the command sleeps for that long first
+pub fn parse_anacrontab(contents: &str) -> Import {
+    let mut import = Import::default();
+    for (i, line) in contents.lines().enumerate() {
+        let lineno = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = env_assignment(line) {
+            import.warn(
+                lineno,
+                format!(
+                    "environment variable {} isn't imported, set it in the command instead",
+                    name
+                ),
+            );
+            continue;
+        }
+        let (fields, command) = match split_fields(line, 3) {
+            Some(parsed) => parsed,
+            None => {
+                import.warn(
+                    lineno,
+                    "skipping, expected 'period delay job-identifier command'".to_string(),
+                );
+                continue;
+            }
+        };
+        let every = match anacron_period(fields[0]) {
+            Some(every) => every,
+            None => {
+                import.warn(lineno, format!("skipping, unknown period '{}'", fields[0]));
+                continue;
+            }
+        };
+        let command = match fields[1].parse::<u64>() {
+            Ok(0) => command.to_string(),
+            Ok(delay) => format!("sleep {} && {}", delay * 60, command),
+            Err(_) => {
+                import.warn(
+                    lineno,
+                    format!("skipping, couldn't parse delay '{}'", fields[1]),
+                );
+                continue;
+            }
+        };
+        import.add(lineno, fields[2].to_string(), &every, command, line);
+    }
+    import
+}
+
+/// converts the special `@` strings crontab supports to a schedule, which all
+/// run at the start of the hour/day they're due
+fn cron_nickname(nickname: &str) -> Option<&'static str> {
+    match nickname {
+        "@hourly" => Some("1 hour"),
+        "@daily" | "@midnight" => Some("1 day at 00:00"),
+        "@weekly" => Some("1 day on sun at 00:00"),
+        "@monthly" => Some("1 month at 00:00"),
+        "@yearly" | "@annually" => Some("1 year at 00:00"),
+        _ => None,
+    }
+}
+
+/// whether a field matches exactly one value, like `5` or `mon`
+fn single(field: &str) -> bool {
+    !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// parses a step over every value, like `*/15`
+fn step(field: &str) -> Option<u64> {
+    field
+        .strip_prefix("*/")?
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+}
+
+/// parses a day of the week field, like `1-5`, `mon,wed,fri` or `0`, where
+/// Sunday is 0 (or 7)
+fn cron_weekdays(field: &str) -> Option<Weekdays> {
+    let day = |value: &str| -> Option<usize> {
+        match value.parse::<usize>() {
+            Ok(day) if day <= 7 => Some(day % 7),
+            Ok(_) => None,
+            Err(_) => DAY_NAMES
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(value)),
+        }
+    };
+    let mut names = vec![];
+    for part in field.split(',') {
+        let (first, last) = match part.split_once('-') {
+            // a range ending on Sunday includes everything after the first day
+            Some((first, last)) => match (day(first)?, day(last)?) {
+                (first, 0) if first > 0 => (first, 7),
+                range => range,
+            },
+            None => (day(part)?, day(part)?),
+        };
+        if last < first {
+            return None;
+        }
+        names.extend((first..=last).map(|day| DAY_NAMES[day % 7]));
+    }
+    Weekdays::from_names(names).ok()
+}
+
+/// converts a crontab schedule (minute, hour, day of month, month, day of week) to
+/// how often it would run, and the time of day/days of the week its pinned to, if the
+/// fields describe a fixed interval
+///
+/// Also returns warnings for anything that changes when converting it, e.g. the minute
+/// of the hour or day of the month its pinned to, or steps which cron restarts at the
+/// start of every hour/day
+fn cron_interval(fields: &[&str]) -> Option<(String, Vec<String>)> {
+    let plural = |n: u64, unit: &str| match n {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    };
+    let number = |field: &str, below: u32| field.parse::<u32>().ok().filter(|n| *n < below);
+    // the time of day, when both the minute and the hour are pinned
+    let at = match (number(fields[0], 60), number(fields[1], 24)) {
+        (Some(minute), Some(hour)) => Some(format!("at {:02}:{:02}", hour, minute)),
+        _ => None,
+    };
+    let every = match fields {
+        ["*", "*", "*", "*", "*"] => Some("1 minute".to_string()),
+        [minute, "*", "*", "*", "*"] => match step(minute) {
+            Some(n) => Some(plural(n, "minute")),
+            None if single(minute) => Some("1 hour".to_string()),
+            None => None,
+        },
+        [minute, hour, "*", "*", "*"] if single(minute) => match (step(hour), &at) {
+            (Some(n), _) => Some(plural(n, "hour")),
+            (None, Some(at)) => Some(format!("1 day {}", at)),
+            (None, None) => None,
+        },
+        [_, _, "*", "*", weekday] => match (&at, cron_weekdays(weekday)) {
+            (Some(at), Some(days)) => Some(format!("1 day on {} {}", days.describe(), at)),
+            _ => None,
+        },
+        [_, _, day, "*", "*"] if single(day) => at.as_ref().map(|at| format!("1 month {}", at)),
+        [_, _, day, month, "*"] if single(day) && single(month) => {
+            at.as_ref().map(|at| format!("1 year {}", at))
+        }
+        _ => None,
+    }?;
+    let schedule = fields.join(" ");
+    let mut warnings = vec![];
+    for (field, unit, per) in [(fields[0], "minute", 60), (fields[1], "hour", 24)] {
+        if let Some(n) = step(field).filter(|n| per % n != 0) {
+            warnings.push(format!(
+                "'{}' restarts at the start of every {}, so it doesn't always run every {} {}s, imported as {}",
+                schedule,
+                match unit {
+                    "minute" => "hour",
+                    _ => "day",
+                },
+                n,
+                unit,
+                every
+            ));
+        }
+    }
+    // evry can pin a time of day, but not a minute of the hour or a day of the month
+    let pinned = match (single(fields[0]), single(fields[1]), single(fields[2])) {
+        (true, true, true) => Some(format!("on day {} of the month", fields[2])),
+        (true, false, _) => Some(format!("at minute {} of the hour", fields[0])),
+        _ => None,
+    };
+    if let Some(pinned) = pinned {
+        warnings.push(format!(
+            "'{}' runs {}, evry runs it {} after it last ran instead",
+            schedule,
+            pinned,
+            every.split(" at ").next().unwrap_or_default()
+        ));
+    }
+    if let Some(day) = fields[2].parse::<u32>().ok().filter(|day| *day > 28) {
+        warnings.push(format!(
+            "'{}' is skipped in months without a day {}, evry runs it every {}",
+            schedule,
+            day,
+            every.split(" at ").next().unwrap_or_default()
+        ));
+    }
+    Some((every, warnings))
+}
+
+/// converts a crontab command, where an unescaped '%' starts the command's standard input
+fn cron_command(command: &str) -> Option<String> {
+    let mut converted = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('%') => converted.push('%'),
+                Some(other) => {
+                    converted.push('\\');
+                    converted.push(other);
+                }
+                None => converted.push('\\'),
+            },
+            '%' => return None,
+            c => converted.push(c),
+        }
+    }
+    Some(converted)
+}
+
+/// picks a tag name for a crontab entry from the program it runs,
+/// e.g. `/usr/bin/backup.sh --all` is `backup.sh`
+fn cron_tag(command: &str) -> String {
+    let program = command.split_whitespace().next().unwrap_or_default();
+    let name: String = program
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "cron".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// parses a (user) crontab, where each job is `minute hour day-of-month month day-of-week command`
+///
+/// Crontab entries don't have names, so each tag is named after the program
+/// it runs, with a number appended if that's already been used
+pub fn parse_crontab(contents: &str) -> Import {
+    let mut import = Import::default();
+    for (i, line) in contents.lines().enumerate() {
+        let lineno = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (every, command, warnings) = if line.starts_with('@') {
+            let (fields, command) = match split_fields(line, 1) {
+                Some(parsed) => parsed,
+                None => {
+                    import.warn(lineno, "skipping, expected '@nickname command'".to_string());
+                    continue;
+                }
+            };
+            match cron_nickname(fields[0]) {
+                Some(every) => (every.to_string(), command, vec![]),
+                None => {
+                    import.warn(
+                        lineno,
+                        format!(
+                            "skipping, {} can't be represented as an interval",
+                            fields[0]
+                        ),
+                    );
+                    continue;
+                }
+            }
+        } else {
+            if let Some(name) = env_assignment(line) {
+                import.warn(
+                    lineno,
+                    format!(
+                        "environment variable {} isn't imported, set it in the command instead",
+                        name
+                    ),
+                );
+                continue;
+            }
+            let (fields, command) = match split_fields(line, 5) {
+                Some(parsed) => parsed,
+                None => {
+                    import.warn(
+                        lineno,
+                        "skipping, expected 'minute hour day-of-month month day-of-week command'"
+                            .to_string(),
+                    );
+                    continue;
+                }
+            };
+            match cron_interval(&fields) {
+                Some((every, warnings)) => (every, command, warnings),
+                None => {
+                    import.warn(
+                        lineno,
+                        format!(
+                            "skipping, '{}' doesn't run at a fixed interval",
+                            fields.join(" ")
+                        ),
+                    );
+                    continue;
+                }
+            }
+        };
+        let command = match cron_command(command) {
+            Some(command) => command,
+            None => {
+                import.warn(
+                    lineno,
+                    "skipping, '%' (standard input) in a command can't be represented".to_string(),
+                );
+                continue;
+            }
+        };
+        for warning in warnings {
+            import.warn(lineno, warning);
+        }
+        let base = cron_tag(&command);
+        let mut tag = base.clone();
+        let mut n = 1;
+        while import.jobs.iter().any(|imported| imported.job.tag == tag) {
+            n += 1;
+            tag = format!("{}-{}", base, n);
+        }
+        import.add(lineno, tag, &every, command, line);
+    }
+    import
+}
+
+/// parses the contents of a crontab/anacrontab, `format` is the name passed to `evry import`
+pub fn parse(format: &str, contents: &str) -> Result<Import, Error> {
+    match format {
+        "anacrontab" => Ok(parse_anacrontab(contents)),
+        "crontab" => Ok(parse_crontab(contents)),
+        other => anyhow::bail!(
+            "Unknown format '{}', expected 'anacrontab' or 'crontab'",
+            other
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_anacrontab() {
+        let import = parse_anacrontab(
+            "SHELL=/bin/sh
+# period delay job-identifier command
+1	5	cron.daily	nice run-parts /etc/cron.daily
+7 0 cron.weekly  nice run-parts  /etc/cron.weekly
+@monthly 0 cron.monthly nice run-parts /etc/cron.monthly
+0 0 never ls
+1 0 cron.daily ls
+",
+        );
+        let summary: Vec<(&str, &str, &str)> = import
+            .jobs
+            .iter()
+            .map(|i| {
+                (
+                    i.job.tag.as_str(),
+                    i.job.every.as_str(),
+                    i.job.command.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "cron.daily",
+                    "1 day",
+                    "sleep 300 && nice run-parts /etc/cron.daily"
+                ),
+                ("cron.weekly", "7 days", "nice run-parts  /etc/cron.weekly"),
+                (
+                    "cron.monthly",
+                    "1 month",
+                    "nice run-parts /etc/cron.monthly"
+                ),
+            ]
+        );
+        assert_eq!(import.warnings.len(), 3);
+        assert!(import.warnings[0].starts_with("line 1: environment variable SHELL"));
+        assert!(import.warnings[1].starts_with("line 6: skipping, unknown period '0'"));
+        assert!(import.warnings[2].contains("'cron.daily' already exists"));
+
+        let rendered = import.render("anacrontab");
+        assert!(rendered.starts_with(
+            "# from anacrontab: 1\t5\tcron.daily\tnice run-parts /etc/cron.daily
+[cron.daily]
+every = 1 day
+command = sleep 300 && nice run-parts /etc/cron.daily
+"
+        ));
+        let jobs: Vec<Job> = import.jobs.into_iter().map(|i| i.job).collect();
+        assert_eq!(jobs::parse_jobs(&rendered).unwrap(), jobs);
+    }
+
+    #[test]
+    fn test_parse_crontab() {
+        let import = parse_crontab(
+            "MAILTO=root
+*/15 * * * * /usr/local/bin/sync.sh --quiet
+30 2 * * * backup.sh
+0 */6 * * * backup.sh --incremental
+0 9 * * mon report
+0 0 1 * * echo 100\\% done
+@weekly cleanup
+@reboot startup
+0 9 * * 1-5 weekdays
+0 9 1 * 1-5 both
+0 9 * * * mail -s hi root%body
+",
+        );
+        let summary: Vec<(&str, &str, &str)> = import
+            .jobs
+            .iter()
+            .map(|i| {
+                (
+                    i.job.tag.as_str(),
+                    i.job.every.as_str(),
+                    i.job.command.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("sync.sh", "15 minutes", "/usr/local/bin/sync.sh --quiet"),
+                ("backup.sh", "1 day at 02:30", "backup.sh"),
+                ("backup.sh-2", "6 hours", "backup.sh --incremental"),
+                ("report", "1 day on mon at 09:00", "report"),
+                ("echo", "1 month at 00:00", "echo 100% done"),
+                ("cleanup", "1 day on sun at 00:00", "cleanup"),
+                (
+                    "weekdays",
+                    "1 day on mon,tue,wed,thu,fri at 09:00",
+                    "weekdays"
+                ),
+            ]
+        );
+        assert_eq!(import.warnings.len(), 6);
+        assert!(import.warnings[0].starts_with("line 1: environment variable MAILTO"));
+        assert!(import.warnings[1].starts_with("line 4: '0 */6 * * *' runs at minute 0"));
+        assert_eq!(
+            import.warnings[2],
+            "line 6: '0 0 1 * *' runs on day 1 of the month, evry runs it 1 month after it last ran instead"
+        );
+        assert!(import.warnings[3].starts_with("line 8: skipping, @reboot"));
+        assert_eq!(
+            import.warnings[4],
+            "line 10: skipping, '0 9 1 * 1-5' doesn't run at a fixed interval"
+        );
+        assert!(import.warnings[5].contains("'%'"));
+        let rendered = import.render("crontab");
+        let jobs: Vec<Job> = import.jobs.into_iter().map(|i| i.job).collect();
+        assert_eq!(jobs::parse_jobs(&rendered).unwrap(), jobs);
+    }
+
+    #[test]
+    fn test_cron_interval() {
+        let interval = |schedule: &str| {
+            let fields: Vec<&str> = schedule.split(' ').collect();
+            cron_interval(&fields).unwrap()
+        };
+        assert_eq!(interval("*/15 * * * *"), ("15 minutes".to_string(), vec![]));
+        assert_eq!(interval("* * * * *"), ("1 minute".to_string(), vec![]));
+        // cron restarts the step at :00, so there's a 4 minute gap every hour
+        let (every, warnings) = interval("*/7 * * * *");
+        assert_eq!(every, "7 minutes");
+        assert_eq!(
+            warnings,
+            vec!["'*/7 * * * *' restarts at the start of every hour, so it doesn't always run every 7 minutes, imported as 7 minutes"]
+        );
+        let (every, warnings) = interval("0 */5 * * *");
+        assert_eq!(every, "5 hours");
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("restarts at the start of every day"));
+        assert!(warnings[1].contains("runs at minute 0 of the hour"));
+        assert_eq!(interval("0 */6 * * *").1.len(), 1);
+        // cron skips months without a 31st
+        let (every, warnings) = interval("0 3 31 * *");
+        assert_eq!(every, "1 month at 03:00");
+        assert_eq!(
+            warnings,
+            vec![
+                "'0 3 31 * *' runs on day 31 of the month, evry runs it 1 month after it last ran instead",
+                "'0 3 31 * *' is skipped in months without a day 31, evry runs it every 1 month"
+            ]
+        );
+        assert_eq!(interval("0 3 28 * *").1.len(), 1);
+        assert_eq!(interval("0 0 29 2 *"), ("1 year at 00:00".to_string(), vec![
+            "'0 0 29 2 *' runs on day 29 of the month, evry runs it 1 year after it last ran instead".to_string(),
+            "'0 0 29 2 *' is skipped in months without a day 29, evry runs it every 1 year".to_string(),
+        ]));
+        assert_eq!(
+            interval("30 2 * * *"),
+            ("1 day at 02:30".to_string(), vec![])
+        );
+        assert_eq!(
+            interval("5 * * * *").1,
+            vec!["'5 * * * *' runs at minute 5 of the hour, evry runs it 1 hour after it last ran instead"]
+        );
+        // days of the week are pinned, Sunday is 0 or 7
+        for (schedule, every) in [
+            ("0 9 * * 1-5", "1 day on mon,tue,wed,thu,fri at 09:00"),
+            ("15 18 * * MON,wed,5", "1 day on mon,wed,fri at 18:15"),
+            ("0 0 * * 0", "1 day on sun at 00:00"),
+            ("0 0 * * 7", "1 day on sun at 00:00"),
+            ("0 0 * * 5-7", "1 day on fri,sat,sun at 00:00"),
+            ("0 0 * * sat-sun", "1 day on sat,sun at 00:00"),
+        ] {
+            assert_eq!(
+                interval(schedule),
+                (every.to_string(), vec![]),
+                "{}",
+                schedule
+            );
+        }
+        for schedule in [
+            "0 9 * * 5-1",
+            "0 9 * * 8",
+            "0 */2 * * 1",
+            "0 9 1 * 1",
+            "0 9 * * */2",
+        ] {
+            let fields: Vec<&str> = schedule.split(' ').collect();
+            assert_eq!(cron_interval(&fields), None, "{}", schedule);
+        }
+    }
+}
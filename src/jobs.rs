@@ -0,0 +1,285 @@
+//! Parses job files, which list tags and how often each of them should run
+//!
+//! Each job is a section named after its tag, followed by `key = value` pairs:
+//!
+//! ```text
+//! # comments start with a '#'
+//! [backup/photos]
+//! every = 1 week
+//! command = rsync -a ~/Pictures remote:Pictures
+//!
+//! [scrapesite]
+//! every = 2 weeks
+//! window = 01:00-05:00
+//!
+//! [report]
+//! every = 1 day at 09:00 in Europe/Berlin
+//! ```
+//!
+//! `every` is required, and is parsed with the same grammar as the duration passed to evry
+//! (so it can be anchored to a time of day, or limited to some days of the week).
+//! `window` is the time of day the job can run in, like --window
+
+use anyhow::{bail, Context, Error, Result};
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    parser,
+    schedule::{Schedule, Window},
+    xdg,
+};
+
+/// A tag, and how often it should run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// name of the tag, like `backup/photos`
+    pub tag: String,
+    /// the duration, as written in the job file
+    pub every: String,
+    /// the parsed duration, along with the `window` from the job file
+    pub schedule: Schedule,
+    /// the command this job runs, if any
+    pub command: Option<String>,
+}
+
+/// where the job file is read from if one isn't passed, `$XDG_CONFIG_HOME/evry/jobs`
+pub fn default_job_file() -> Result<PathBuf, Error> {
+    Ok(xdg::config_home()?.join("evry").join("jobs"))
+}
+
+/// reads and parses a job file
+pub fn read_job_file(path: &Path) -> Result<Vec<Job>, Error> {
+    parse_job_file(path, &read_contents(path)?)
+}
+
+/// reads a job file, without parsing it
+pub fn read_contents(path: &Path) -> Result<String, Error> {
+    read_to_string(path).context(format!("Could not read job file {}", path.display()))
+}
+
+/// parses the contents of the job file at `path`
+pub fn parse_job_file(path: &Path, contents: &str) -> Result<Vec<Job>, Error> {
+    parse_jobs(contents).context(format!("Could not parse job file {}", path.display()))
+}
+
+/// if a job file has a section for a tag, without parsing the rest of it
+pub fn lists_tag(contents: &str, tag: &str) -> bool {
+    contents.lines().any(|line| {
+        line.trim()
+            .strip_prefix('[')
+            .and_then(|section| section.strip_suffix(']'))
+            .is_some_and(|section| section.trim() == tag)
+    })
+}
+
+/// formats a job as a section of a job file
+pub fn format_job(job: &Job) -> String {
+    let mut section = format!("[{}]\nevery = {}\n", job.tag, job.every);
+    if let Some(command) = &job.command {
+        section.push_str(&format!("command = {}\n", command));
+    }
+    if let Some(window) = &job.schedule.window {
+        section.push_str(&format!("window = {}\n", window.describe()));
+    }
+    section
+}
+
+/// a job which is still being parsed
+#[doc(hidden)]
+struct PartialJob {
+    tag: String,
+    line: usize,
+    every: Option<String>,
+    command: Option<String>,
+    window: Option<Window>,
+}
+
+impl PartialJob {
+    /// makes sure all the required keys were set
+    fn finish(self) -> Result<Job, Error> {
+        let every = self.every.context(format!(
+            "line {}: job '{}' is missing 'every'",
+            self.line, self.tag
+        ))?;
+        let mut schedule = parser::parse_schedule(&every).context(format!(
+            "line {}: couldn't parse '{}' into a duration for job '{}'",
+            self.line, every, self.tag
+        ))?;
+        schedule.window = self.window;
+        Ok(Job {
+            tag: self.tag,
+            every,
+            schedule,
+            command: self.command,
+        })
+    }
+}
+
+/// parses the contents of a job file
+pub fn parse_jobs(contents: &str) -> Result<Vec<Job>, Error> {
+    let mut jobs: Vec<Job> = vec![];
+    let mut current: Option<PartialJob> = None;
+    for (i, line) in contents.lines().enumerate() {
+        let lineno = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[') {
+            let tag = section
+                .strip_suffix(']')
+                .context(format!(
+                    "line {}: expected a ']' after the tag name",
+                    lineno
+                ))?
+                .trim();
+            if tag.is_empty() {
+                bail!("line {}: tag name is empty", lineno);
+            }
+            if let Some(job) = current.take() {
+                jobs.push(job.finish()?);
+            }
+            if jobs.iter().any(|job| job.tag == tag) {
+                bail!("line {}: job '{}' is defined more than once", lineno, tag);
+            }
+            current = Some(PartialJob {
+                tag: tag.to_string(),
+                line: lineno,
+                every: None,
+                command: None,
+                window: None,
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .context(format!("line {}: expected 'key = value'", lineno))?;
+        let job = current.as_mut().context(format!(
+            "line {}: '{}' must be inside a [tag] section",
+            lineno,
+            key.trim()
+        ))?;
+        let value = value.trim().to_string();
+        match key.trim() {
+            "every" => job.every = Some(value),
+            "command" => job.command = Some(value),
+            "window" => {
+                job.window = Some(
+                    value
+                        .parse()
+                        .map_err(|e| anyhow::anyhow!("line {}: {}", lineno, e))?,
+                )
+            }
+            other => bail!("line {}: unknown key '{}'", lineno, other),
+        }
+    }
+    if let Some(job) = current.take() {
+        jobs.push(job.finish()?);
+    }
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_jobs() {
+        let jobs = parse_jobs(
+            "# backups
+[backup/photos]
+every = 1 week
+command = rsync -a ~/Pictures remote:Pictures
+
+[scrapesite]
+every=2 weeks
+window = 22:00-2:00
+
+[report]
+every = 1 day at 02:30 in Europe/Berlin
+",
+        )
+        .unwrap();
+        assert_eq!(
+            jobs,
+            vec![
+                Job {
+                    tag: "backup/photos".to_string(),
+                    every: "1 week".to_string(),
+                    schedule: Schedule {
+                        every: 604800000,
+                        ..Default::default()
+                    },
+                    command: Some("rsync -a ~/Pictures remote:Pictures".to_string()),
+                },
+                Job {
+                    tag: "scrapesite".to_string(),
+                    every: "2 weeks".to_string(),
+                    schedule: Schedule {
+                        every: 1209600000,
+                        window: Some(Window {
+                            start: 22 * 3600,
+                            end: 2 * 3600,
+                        }),
+                        ..Default::default()
+                    },
+                    command: None,
+                },
+                Job {
+                    tag: "report".to_string(),
+                    every: "1 day at 02:30 in Europe/Berlin".to_string(),
+                    schedule: Schedule {
+                        every: 86400000,
+                        at: Some(2 * 3600 + 30 * 60),
+                        timezone: Some("Europe/Berlin".to_string()),
+                        ..Default::default()
+                    },
+                    command: None,
+                },
+            ]
+        );
+        // business days and the days of the week are kept as well
+        let jobs_with_days = parse_jobs(
+            "[standup]\nevery = 1 business day\n[gym]\nevery = 1 day on mon,Wednesday at 18:00",
+        )
+        .unwrap();
+        assert_eq!(jobs_with_days[0].schedule.business_days, 1);
+        assert_eq!(jobs_with_days[0].schedule.every, 86400000);
+        let gym = &jobs_with_days[1].schedule;
+        assert_eq!(gym.days.unwrap().describe(), "mon,wed");
+        assert_eq!(gym.at, Some(18 * 3600));
+        assert_eq!(
+            format_job(&jobs_with_days[1]),
+            "[gym]\nevery = 1 day on mon,Wednesday at 18:00\n"
+        );
+        assert_eq!(
+            format_job(&jobs[1]),
+            "[scrapesite]\nevery = 2 weeks\nwindow = 22:00-02:00\n"
+        );
+    }
+
+    #[test]
+    fn test_lists_tag() {
+        let contents = "[backup/photos]\nevery = 1 week\n\n  [ scrapesite ]\nevery = 1 fortnight\n";
+        assert!(lists_tag(contents, "backup/photos"));
+        assert!(lists_tag(contents, "scrapesite"));
+        assert!(!lists_tag(contents, "backup"));
+        assert!(!lists_tag("# [backup]", "backup"));
+    }
+
+    #[test]
+    fn test_parse_jobs_errors() {
+        assert!(parse_jobs("every = 1d").is_err());
+        assert!(parse_jobs("[a]\ncommand = ls").is_err());
+        assert!(parse_jobs("[a]\nevery = 1 fortnight").is_err());
+        assert!(parse_jobs("[a]\nevery = 1d\n[a]\nevery = 2d").is_err());
+        assert!(parse_jobs("[a]\nevry = 1d").is_err());
+        assert!(parse_jobs("[a\nevery = 1d").is_err());
+        assert!(parse_jobs("[a]\nevery = 1d\nwindow = 1am-5am").is_err());
+        assert!(parse_jobs("[a]\nevery = 1 hour at 02:30").is_err());
+        assert!(parse_jobs("[a]\nevery = 1 day on monkey").is_err());
+    }
+}
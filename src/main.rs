@@ -27,30 +27,142 @@
 //!
 //! ... and even though that tries to run the command every 60 seconds, `evry` exits with an unsuccessful exit code, so `run command` would only get run once per month.
 //!
-//! The `-runcommand` is just an arbitrary tag name so that `evry` can save metadata about a command to run/job. Can be chosen arbitrarily, its only use is to uniquely identify runs of `evry`, and save a metadata file to your [local data directory](https://docs.rs/app_dirs/1.2.1/app_dirs/)
+//! The `-runcommand` is just an arbitrary tag name so that `evry` can save metadata about a command to run/job. Can be chosen arbitrarily, its only use is to uniquely identify runs of `evry`, and save a metadata file to your local state directory (`$XDG_STATE_HOME/evry/tags`)
 //!
 //! Since this doesn't run in a larger context and its just a bash script, if a command fails, you can remove the tag file, to reset it to run again later (since if the file doesn't exist, `evry` assumes its a new task)
 
 use std::env;
-use std::io::Write;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 use std::string::String;
 
-use anyhow::{Context, Error, Result};
+use anyhow::{bail, Context, Error, Result};
+use cli::{Command, UsageError};
 extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
+mod cli;
+mod completions;
+mod export;
 mod file;
+mod import;
+mod jobs;
+mod metrics;
 mod parser;
 mod printer;
+mod schedule;
+mod status;
+mod tz;
 mod utils;
+mod xdg;
 
-#[derive(Debug)]
-enum Command {
-    Location,
-    Duration,
+/// what to do the first time a tag is run, when its tag file doesn't exist
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum FirstRun {
+    /// run immediately
+    #[default]
     Run,
+    /// wait one full interval before running
+    Defer,
+    /// wait a random delay within the interval, so new jobs don't all run at once
+    Random,
+}
+
+impl FirstRun {
+    /// the name of the policy, as passed to --first-run
+    fn describe(&self) -> &'static str {
+        match self {
+            FirstRun::Run => "run",
+            FirstRun::Defer => "defer",
+            FirstRun::Random => "random",
+        }
+    }
+
+    /// when to save a new tag as last running, so that its first run is
+    /// deferred by this policy. `None` if it should run now
+    fn deferred_last_run(&self, now: u128, run_every: u128) -> Option<u128> {
+        let delay = match self {
+            FirstRun::Run => return None,
+            FirstRun::Defer => run_every,
+            FirstRun::Random => utils::random_millis_below(run_every),
+        };
+        Some((now + delay).saturating_sub(run_every))
+    }
+}
+
+impl FromStr for FirstRun {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "run" => Ok(FirstRun::Run),
+            "defer" => Ok(FirstRun::Defer),
+            "random" => Ok(FirstRun::Random),
+            _ => bail!(
+                "Unknown first run policy '{}', expected one of: run, defer, random",
+                s
+            ),
+        }
+    }
+}
+
+/// what to do if a tag file holds a time in the future, e.g. if the
+/// clock went backwards or the tag was synced from another machine
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ClockSkew {
+    /// treat the tag as if it ran now, and save the current time to the tag file
+    #[default]
+    JustRun,
+    /// treat the tag as if it never ran, so it runs now
+    NeverRun,
+    /// exit with an error, without changing the tag file
+    Error,
+}
+
+impl ClockSkew {
+    /// the name of the policy, as passed to --clock-skew
+    fn describe(&self) -> &'static str {
+        match self {
+            ClockSkew::JustRun => "just-run",
+            ClockSkew::NeverRun => "never-run",
+            ClockSkew::Error => "error",
+        }
+    }
+
+    /// for a tag whose tag file says it last ran in the future, returns how
+    /// long ago its treated as running, and when its treated as last running
+    fn resolve(&self, tag: &str, last_run: u128, now: u128) -> Result<(u128, u128), Error> {
+        match self {
+            ClockSkew::Error => bail!(
+                "Tag file for '{}' is in the future, run 'evry reset -{}' to remove it",
+                tag,
+                tag
+            ),
+            // as if it never ran, so its been longer than any duration
+            ClockSkew::NeverRun => Ok((u128::MAX, last_run)),
+            ClockSkew::JustRun => Ok((0, now)),
+        }
+    }
+}
+
+impl FromStr for ClockSkew {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "just-run" => Ok(ClockSkew::JustRun),
+            "never-run" => Ok(ClockSkew::NeverRun),
+            "error" => Ok(ClockSkew::Error),
+            _ => bail!(
+                "Unknown clock skew policy '{}', expected one of: just-run, never-run, error",
+                s
+            ),
+        }
+    }
 }
 
 /// parses the user input; flags/environment variables
@@ -58,180 +170,703 @@ enum Command {
 struct Args {
     /// unparsed, string representation of a date from the user
     raw_date: String,
-    /// if EVRY_DEBUG=1 was set
+    /// the words after the command, e.g. `systemd <target directory>` for export
+    arguments: Vec<String>,
+    /// if --debug/EVRY_DEBUG or --json/EVRY_JSON was set
     debug: bool,
-    /// if EVRY_JSON=1 was set
-    json: bool,
+    /// how to describe durations, from --format or EVRY_DURATION_FORMAT
+    duration_format: utils::DurationFormat,
+    /// if the user passed --format, the duration command prints
+    /// the duration in that format instead of in seconds
+    format_flag: bool,
+    /// if a format was set with --format or EVRY_DURATION_FORMAT, rather than the default
+    format_set: bool,
+    /// if --new was passed, allowing a tag which doesn't exist yet in strict mode
+    new: bool,
+    /// if --strict/EVRY_STRICT was set, so unknown tags have to be created with --new
+    strict: bool,
+    /// if --monotonic/EVRY_MONOTONIC was set, so the boot time is saved and
+    /// used to measure the interval within the same boot
+    monotonic: bool,
+    /// if --active/EVRY_ACTIVE was set, so only the time the machine
+    /// wasn't suspended counts towards the interval
+    active: bool,
+    /// if --dry-run/EVRY_DRY_RUN was set, so tag files aren't written to
+    dry_run: bool,
+    /// the time of day the tag can run in, from --window or EVRY_WINDOW
+    window: Option<schedule::Window>,
+    /// what to do if the tag file doesn't exist, from --first-run or EVRY_FIRST_RUN
+    first_run: FirstRun,
+    /// what to do if the tag file is in the future, from --clock-skew or EVRY_CLOCK_SKEW
+    clock_skew: ClockSkew,
+    /// how many intervals a tag can go without running before
+    /// the audit command considers it stale, from --grace or EVRY_AUDIT_GRACE
+    grace: f64,
+    /// the job file to use if one isn't passed, from --job-file or EVRY_JOB_FILE
+    job_file: Option<String>,
+    /// where the metrics command writes to, from --metrics-file or EVRY_METRICS_FILE
+    metrics_file: Option<String>,
+    /// file to log durations which couldn't be parsed to, from --parse-error-log or EVRY_PARSE_ERROR_LOG
+    parse_error_log: Option<String>,
     // if the user wants to print location/duration instead of running normally
     command: Command,
     /// tagfile to read/write from, uniquely identifies this job
-    tag: file::Tag,
+    /// (not required for the duration command)
+    tag: Option<file::Tag>,
 }
 
-impl Args {
-    /// prints the help message
-    fn help() {
-        println!(
-            "A tool to manually run commands -- periodically.
-Uses shell exit codes to determine control flow in shell scripts
-
-Usage:
-  evry <describe duration>... <-tagname>
-  evry location <-tagname>
-  evry duration <describe duration...>
-  evry help
-
-Best explained with an example:
-
-evry 2 weeks -scrapesite && wget \"https://\" -o ....
-
-In other words, run the wget command every 2 weeks.
-
-evry exits with an unsuccessful exit code if the command has
-been run in the last 2 weeks, which means the wget command wouldn't run.
-
-When evry exits with a successful exit code, it saves the current time
-to a metadata file for that tag (-scrapesite). That way, when evry
-is run again with that tag, it can compare the current time against that file.
+/// The exit codes evry uses, so scripts can tell "skip" apart from "something is broken"
+///
+/// These are stable, new failure classes get a new code
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    /// the tag should run (or the command succeeded)
+    Success = 0,
+    /// an error which doesn't fit any of the other classes
+    Error = 1,
+    /// the tag ran within the duration, so it shouldn't run
+    NotDue = 2,
+    /// the audit command found stale tags
+    Stale = 3,
+    /// the duration couldn't be parsed
+    ParseError = 4,
+    /// a tag file, the data directory or another file couldn't be read or written
+    StorageError = 5,
+    /// another evry process held the lock on the data directory for too long
+    LockContention = 6,
+    /// another evry process is already checking the same tag
+    AlreadyRunning = 7,
+    /// the arguments couldn't be parsed. This is unsuccessful, so if the user is
+    /// doing some complex argparsing in a bash script and this fails to parse
+    /// the arguments, it doesn't run the dependent command accidentally
+    Usage = 10,
+}
 
-location prints the computed tag file location
+impl Exit {
+    /// every exit code, in the order they're shown in the help message
+    const ALL: [Exit; 9] = [
+        Exit::Success,
+        Exit::Error,
+        Exit::NotDue,
+        Exit::Stale,
+        Exit::ParseError,
+        Exit::StorageError,
+        Exit::LockContention,
+        Exit::AlreadyRunning,
+        Exit::Usage,
+    ];
 
-duration just lets you use this as a duration parser, without interacting with the filesystem
-it prints the parsed duration in seconds. Running with JSON mode prints more formats
+    /// description used in the help message
+    fn describe(&self) -> &'static str {
+        match self {
+            Exit::Success => "the tag should run (or the command succeeded)",
+            Exit::Error => "an unexpected error occurred",
+            Exit::NotDue => "the tag ran within the duration, so it shouldn't run",
+            Exit::Stale => "audit found stale tags",
+            Exit::ParseError => "the duration couldn't be parsed",
+            Exit::StorageError => "a tag file (or another file) couldn't be read or written",
+            Exit::LockContention => "another evry process held the lock on the data directory",
+            Exit::AlreadyRunning => "another evry process is already checking this tag",
+            Exit::Usage => "the arguments couldn't be parsed",
+        }
+    }
 
-See https://github.com/seanbreckenridge/evry for more examples."
-        );
-        // exit with an unsuccessful exit code so if user is doing some complex argparsing
-        // in a bash script, and this fails to parse the arguments,
-        // this fails and doesn't run the dependent command accidentally
-        exit(10);
-    }
-
-    /// parses command-line user input/environment variables
-    fn parse_args(dir_info: &file::LocalDir) -> Result<Self, Error> {
-        // get arguments (remove binary name)
-        let args: Vec<String> = env::args().skip(1).collect();
-        // if user asked for help
-        if args
-            .iter()
-            .filter(|&arg| arg == "help" || arg == "--help")
-            .count()
-            > 0
+    /// classifies an error which caused evry to exit
+    fn from_error(e: &Error) -> Self {
+        if e.is::<UsageError>() {
+            Exit::Usage
+        } else if e
+            .chain()
+            .any(|cause| cause.is::<pest::error::Error<parser::Rule>>())
         {
-            Args::help()
+            Exit::ParseError
+        } else if e.chain().any(|cause| cause.is::<file::LockError>()) {
+            Exit::LockContention
+        } else if e.chain().any(|cause| cause.is::<file::AlreadyRunning>()) {
+            Exit::AlreadyRunning
+        } else if e.chain().any(|cause| cause.is::<io::Error>()) {
+            Exit::StorageError
+        } else {
+            Exit::Error
         }
-        // split args arguments into tag/other strings
-        let (tag_vec, other_vec): (_, Vec<_>) =
-            args.into_iter().partition(|arg| arg.starts_with('-'));
+    }
+
+    /// the exit codes and their descriptions, for the help message
+    fn table() -> Vec<(i32, &'static str)> {
+        Exit::ALL
+            .iter()
+            .map(|exit| (*exit as i32, exit.describe()))
+            .collect()
+    }
+}
+
+impl Args {
+    /// builds the arguments from the parsed command-line options/environment variables
+    fn parse_args(parsed: cli::Parsed, dir_info: &file::LocalDir) -> Result<Self, Error> {
+        let other_vec = &parsed.words;
+        let tag_vec = &parsed.tags;
         if other_vec.is_empty() {
-            eprintln!("Error: Must provide a duration string or a command\n");
-            Args::help()
-        }
-        let first_arg = &other_vec[0];
-        let command: Command = match first_arg.as_str() {
-            "location" => Command::Location,
-            "duration" => Command::Duration,
-            _ => Command::Run,
-        };
+            bail!(UsageError(
+                "Must provide a duration string or a command".to_string()
+            ));
+        }
+        let command = parsed.command();
         let date_string = match command {
-            Command::Location | Command::Duration => other_vec[1..].join(" "),
-            _ => other_vec.join(" "),
+            Command::Run => other_vec.join(" "),
+            _ => other_vec[1..].join(" "),
+        };
+        let arguments = match command {
+            Command::Run => vec![],
+            _ => other_vec[1..].to_vec(),
         };
-        if tag_vec.is_empty() && !matches!(command, Command::Duration) {
-            eprintln!("Error: Must provide a tag name using a hyphen or a command\n");
-            Args::help()
+        if tag_vec.is_empty()
+            && !matches!(
+                command,
+                Command::Duration
+                    | Command::List
+                    | Command::Migrate
+                    | Command::Status
+                    | Command::Audit
+                    | Command::Metrics
+                    | Command::Export
+                    | Command::Import
+                    | Command::Completions
+            )
+        {
+            bail!(UsageError(
+                "Must provide a tag name using a hyphen or a command".to_string()
+            ));
         }
-        // parse tag, remove the first character ('-') from the tag
-        let tag: String = tag_vec
-            .iter()
-            .map(|arg| arg.chars().skip(1).collect::<String>())
-            .collect::<Vec<String>>()
-            .join("_");
-        // if user didnt ask for duration, they have to provide a tag
-        if tag.chars().count() == 0 && first_arg != "duration" {
-            eprintln!("Error: passed tag was an empty string\n");
+        // multiple tags are joined into one, like '-backup -photos' is 'backup_photos'
+        let mut tag_name: String = tag_vec.join("_");
+        // allow a trailing separator when referring to a namespace, like 'evry reset -backup/'
+        if matches!(command, Command::List | Command::Reset) {
+            tag_name = tag_name
+                .trim_end_matches(file::NAMESPACE_SEPARATOR)
+                .to_string();
         }
+        // if user didnt ask for duration, they have to provide a tag
+        // 'evry location -' (an empty tag) prints the data directory,
+        // 'evry list -' lists every tag
+        let tag = if tag_vec.is_empty()
+            || (tag_name.is_empty() && matches!(command, Command::Location | Command::List))
+        {
+            None
+        } else {
+            Some(file::Tag::new(tag_name, dir_info)?)
+        };
         match command {
-            Command::Location => (),
+            Command::Location
+            | Command::List
+            | Command::Reset
+            | Command::Migrate
+            | Command::Status
+            | Command::Audit
+            | Command::Metrics
+            | Command::Export
+            | Command::Import
+            | Command::Completions => (),
             _ => {
                 if date_string.chars().count() == 0 {
-                    eprintln!("Error: passed duration was an empty string");
-                    Args::help()
+                    bail!(UsageError(
+                        "passed duration was an empty string".to_string()
+                    ));
                 }
             }
         }
-        let json = env::var("EVRY_JSON").is_ok();
+        let grace = match parsed.value("grace") {
+            Some(grace) => match grace.parse::<f64>() {
+                Ok(grace) if grace > 0.0 => grace,
+                _ => bail!(UsageError(format!(
+                    "Expected a positive number for the grace factor, like '--grace 1.5', got '{}'",
+                    grace
+                ))),
+            },
+            None => status::DEFAULT_GRACE,
+        };
+        let first_run = match parsed.value("first-run") {
+            Some(policy) => policy
+                .parse::<FirstRun>()
+                .map_err(|e| UsageError(e.to_string()))?,
+            None => FirstRun::default(),
+        };
+        let clock_skew = match parsed.value("clock-skew") {
+            Some(policy) => policy
+                .parse::<ClockSkew>()
+                .map_err(|e| UsageError(e.to_string()))?,
+            None => ClockSkew::default(),
+        };
+        let window = match parsed.value("window") {
+            Some(window) => Some(
+                window
+                    .parse::<schedule::Window>()
+                    .map_err(|e| UsageError(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let duration_format = match parsed.value("format") {
+            Some(format) => format
+                .parse::<utils::DurationFormat>()
+                .map_err(|e| UsageError(e.to_string()))?,
+            None => utils::DurationFormat::default(),
+        };
         Ok(Args {
             command,
             raw_date: date_string,
-            // specifying EVRY_JSON automatically enables debug as well
+            arguments,
+            duration_format,
+            format_flag: parsed.passed("format"),
+            format_set: parsed.flag("format"),
+            new: parsed.passed("new"),
+            strict: parsed.flag("strict"),
+            monotonic: parsed.flag("monotonic"),
+            active: parsed.flag("active"),
+            dry_run: parsed.flag("dry-run"),
+            window,
+            first_run,
+            clock_skew,
+            grace,
+            job_file: parsed.value("job-file"),
+            metrics_file: parsed.value("metrics-file"),
+            parse_error_log: parsed.value("parse-error-log"),
+            // specifying --json automatically enables debug as well
             // otherwise evry is supposed to remain silent -- its not meant to print anything
-            debug: json | env::var("EVRY_DEBUG").is_ok(),
-            json,
-            tag: file::Tag::new(tag.to_string(), dir_info),
+            debug: parsed.flag("json") || parsed.flag("debug"),
+            tag,
+        })
+    }
+
+    /// the job file passed as an argument, or from --job-file/EVRY_JOB_FILE, or the default location
+    fn job_file(&self, passed: Option<&str>) -> Result<PathBuf, Error> {
+        Ok(match passed {
+            Some(job_file) if !job_file.is_empty() => PathBuf::from(job_file),
+            _ => match &self.job_file {
+                Some(job_file) => PathBuf::from(job_file),
+                None => jobs::default_job_file()?,
+            },
         })
     }
+
+    /// the tag for this job, errors if the user didn't pass one
+    fn tag(&self) -> Result<&file::Tag, Error> {
+        self.tag
+            .as_ref()
+            .context("Must provide a tag name using a hyphen")
+    }
 }
 
 /// encapsulates the logic for evry, printing logs to the printer
 /// if debug is enabled.
 /// Returns an exit code to signify what to do
-fn evry(dir_info: file::LocalDir, cli: Args, printer: &mut printer::Printer) -> Result<i32, Error> {
+fn evry(
+    dir_info: file::LocalDir,
+    cli: Args,
+    printer: &mut printer::Printer,
+) -> Result<Exit, Error> {
+    printer.report.tag = cli.tag.as_ref().map(|tag| tag.name.clone());
+    printer.report.dry_run = cli.dry_run;
     if cli.debug {
-        printer.echo("tag_name", &cli.tag.name);
+        if let Some(tag) = &cli.tag {
+            printer.echo("tag_name", &tag.name);
+        }
+        printer.echo("data_directory", &dir_info.data_dir.display().to_string());
+        printer.echo("data_directory_source", dir_info.source.describe());
+        if cli.dry_run {
+            printer.echo("log", "Dry run, tag files won't be written to");
+        }
+        if dir_info.source == file::DirSource::Legacy {
+            printer.echo(
+                "log",
+                "Using the legacy data directory, run 'evry migrate' to move your tags to the current location",
+            );
+        }
+        for path in dir_info.skipped_files().unwrap_or_default() {
+            printer.echo(
+                "log",
+                &format!(
+                    "Skipping {}, its name can't be decoded into a tag name",
+                    path.display()
+                ),
+            );
+        }
+    }
 
-        let dir_path: String = dir_info.data_dir.into_os_string().into_string().unwrap();
-        printer.echo("data_directory", &dir_path);
+    if matches!(cli.command, Command::Migrate) {
+        let (new_dir, backup_dir) = dir_info.migrate_dirs()?;
+        if cli.dry_run {
+            printer.echo(
+                "dry_run",
+                &format!(
+                    "Dry run, not moving tags from {} to {} (a backup would be saved to {})",
+                    dir_info.data_dir.display(),
+                    new_dir.display(),
+                    backup_dir.display()
+                ),
+            );
+            return Ok(Exit::Success);
+        }
+        let _lock = dir_info.lock()?;
+        dir_info.migrate_to(&new_dir, &backup_dir)?;
+        printer.echo(
+            "log",
+            &format!(
+                "Moved tags from {} to {} (a backup was saved to {})",
+                dir_info.data_dir.display(),
+                new_dir.display(),
+                backup_dir.display()
+            ),
+        );
+        return Ok(Exit::Success);
     }
 
     if matches!(cli.command, Command::Location) {
         // causes an early exit, print directly instead of using the printer
         // user is probably trying to use this to compute the location like
         // SHELLVAR="$(evry location -tagname)"
-        println!("{}", cli.tag.path);
-        return Ok(0);
+        match &cli.tag {
+            Some(tag) => println!("{}", tag.path.display()),
+            None => println!("{}", dir_info.data_dir.display()),
+        }
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::List) {
+        for name in dir_info.list_tags(cli.tag.as_ref())? {
+            println!("{}", name);
+        }
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::Status) {
+        let jobs = jobs::read_job_file(&cli.job_file(Some(&cli.raw_date))?)?;
+        let now = utils::epoch_millis().context("Couldn't get current time")?;
+        let (statuses, errors) = status::job_statuses(&jobs, &dir_info, now);
+        warn_unreadable(printer, &errors);
+        if printer.is_stderr() {
+            // unless a format was passed, use the shorter largest-unit format for the table
+            let format = match cli.format_set {
+                true => cli.duration_format,
+                false => utils::DurationFormat::Largest(1),
+            };
+            println!("{}", status::format_table(&statuses, now, &format));
+        } else {
+            printer.output("status", &statuses);
+        }
+        return Ok(match errors.is_empty() {
+            true => Exit::Success,
+            false => Exit::Error,
+        });
     }
 
-    // parse duration string
-    let run_every = match parser::parse_time(&cli.raw_date) {
-        Ok(time) => time,
-        Err(_e) => {
+    if matches!(cli.command, Command::Audit) {
+        // the job file is optional, since tags save the interval they were run with
+        let job_file = cli.job_file(Some(&cli.raw_date))?;
+        let jobs = match job_file.exists() || !cli.raw_date.is_empty() {
+            true => jobs::read_job_file(&job_file)?,
+            false => vec![],
+        };
+        let now = utils::epoch_millis().context("Couldn't get current time")?;
+        let (stale, errors) = status::audit(&jobs, &dir_info, now, cli.grace)?;
+        warn_unreadable(printer, &errors);
+        if printer.is_stderr() {
+            for tag in &stale {
+                println!(
+                    "{}: last ran {} ago, expected every {}",
+                    tag.tag,
+                    cli.duration_format.format(tag.age_ms),
+                    cli.duration_format.format(tag.interval_ms)
+                );
+            }
+        } else {
+            printer.output("stale", &stale);
+        }
+        if cli.debug {
+            printer.echo(
+                "log",
+                &format!("{} stale tag(s), grace factor {}", stale.len(), cli.grace),
+            );
+        }
+        return Ok(if !stale.is_empty() {
+            Exit::Stale
+        } else if !errors.is_empty() {
+            Exit::Error
+        } else {
+            Exit::Success
+        });
+    }
+
+    if matches!(cli.command, Command::Metrics) {
+        let output = match cli.raw_date.as_str() {
+            "" => cli.metrics_file.clone().ok_or_else(|| {
+                UsageError(
+                    "Must pass a file to write metrics to, or set --metrics-file".to_string(),
+                )
+            })?,
+            output => output.to_string(),
+        };
+        let (tags, errors) = metrics::read_tags(&dir_info)?;
+        warn_unreadable(printer, &errors);
+        let contents = metrics::render(&tags);
+        if output == "-" {
+            print!("{}", contents);
+        } else {
+            metrics::write_atomic(Path::new(&output), &contents)?;
+            if cli.debug {
+                printer.echo("log", &format!("Wrote metrics to {}", output));
+            }
+        }
+        return Ok(match errors.is_empty() {
+            true => Exit::Success,
+            false => Exit::Error,
+        });
+    }
+
+    if matches!(cli.command, Command::Export) {
+        let (target_dir, job_file) = match cli.arguments.as_slice() {
+            [format, target_dir] if format == "systemd" => (target_dir, None),
+            [format, target_dir, job_file] if format == "systemd" => {
+                (target_dir, Some(job_file.as_str()))
+            }
+            _ => bail!(UsageError(
+                "Expected 'evry export systemd <target directory> [job file]'".to_string()
+            )),
+        };
+        // evry checks whether jobs which systemd can't schedule by itself are due
+        let evry = env::current_exe().context("Couldn't find the evry binary")?;
+        for job in jobs::read_job_file(&cli.job_file(job_file)?)? {
+            match export::systemd_units(&job, &evry.display().to_string()) {
+                Some(units) => {
+                    for path in export::write_units(&units, Path::new(target_dir))? {
+                        println!("{}", path);
+                    }
+                }
+                None => printer.echo(
+                    "warning",
+                    &format!("Skipping job '{}', it doesn't have a command", job.tag),
+                ),
+            }
+        }
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::Import) {
+        let (format, path) = match cli.arguments.as_slice() {
+            [format] => (format, None),
+            [format, path] => (format, Some(path)),
+            _ => bail!(UsageError(
+                "Expected 'evry import <anacrontab|crontab> [file]'".to_string()
+            )),
+        };
+        let contents = match path {
+            Some(path) => fs::read_to_string(path).context(format!("Could not read {}", path))?,
+            None => {
+                let mut contents = String::new();
+                io::stdin()
+                    .read_to_string(&mut contents)
+                    .context("Could not read from stdin")?;
+                contents
+            }
+        };
+        let import = import::parse(format, &contents).map_err(|e| UsageError(e.to_string()))?;
+        for warning in &import.warnings {
+            printer.echo("warning", warning);
+        }
+        print!("{}", import.render(format));
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::Completions) {
+        let script = match cli.arguments.as_slice() {
+            [shell] => completions::script(shell).map_err(|e| UsageError(e.to_string()))?,
+            _ => bail!(UsageError(format!(
+                "Expected 'evry completions <{}>'",
+                completions::SHELLS.join("|")
+            ))),
+        };
+        print!("{}", script);
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::Outcome) {
+        let tag = cli.tag()?;
+        let exit_code = cli.raw_date.parse::<i32>().map_err(|_| {
+            UsageError(format!(
+                "Expected an exit code, like 'evry outcome 0 -{}', got '{}'",
+                tag.name, cli.raw_date
+            ))
+        })?;
+        if !tag.file_exists() {
+            bail!("Tag '{}' has never run, can't save its outcome", tag.name);
+        }
+        let _lock = dir_info.lock()?;
+        let now = utils::epoch_millis().context("Couldn't get current time")?;
+        let mut state = tag.read_state()?;
+        state.set(file::EXIT_CODE_KEY, exit_code);
+        state.set(file::EXITED_AT_KEY, now);
+        if exit_code == 0 {
+            state.set(file::LAST_SUCCESS_KEY, now);
+        }
+        if cli.dry_run {
+            printer.echo(
+                "dry_run",
+                &format!(
+                    "Dry run, not saving exit code {} for tag '{}'",
+                    exit_code, tag.name
+                ),
+            );
+        } else {
+            tag.write_state(&state)?;
+            if cli.debug {
+                printer.echo(
+                    "log",
+                    &format!("Saved exit code {} for tag '{}'", exit_code, tag.name),
+                );
+            }
+        }
+        return Ok(Exit::Success);
+    }
+
+    if matches!(cli.command, Command::Reset) {
+        let tag = cli.tag()?;
+        // so a tag isn't removed while another process is checking it
+        let _lock = dir_info.lock()?;
+        let removed = match cli.dry_run {
+            true => dir_info.list_tags(Some(tag))?,
+            false => tag.remove(&dir_info)?,
+        };
+        if cli.dry_run {
+            for name in &removed {
+                printer.echo("dry_run", &format!("Dry run, not removing tag '{}'", name));
+            }
+        } else if cli.debug {
+            for name in &removed {
+                printer.echo("log", &format!("Removed tag '{}'", name));
+            }
+        }
+        if removed.is_empty() {
+            printer.echo("error", &format!("No such tag or namespace '{}'", tag.name));
+            return Ok(Exit::Error);
+        }
+        return Ok(Exit::Success);
+    }
+
+    // parse duration string, and load the timezone it runs in
+    let parsed = parser::parse_schedule(&cli.raw_date).and_then(|schedule| {
+        let zone = tz::Zone::load(schedule.timezone.as_deref())?;
+        Ok((schedule, zone))
+    });
+    let (mut schedule, zone) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            // syntax errors from pest span multiple lines, so they aren't included
+            let reason = match e.is::<pest::error::Error<parser::Rule>>() {
+                true => String::new(),
+                false => format!(": {}", e),
+            };
             printer.echo(
                 "error",
-                &format!("couldn't parse '{}' into a duration", cli.raw_date),
+                &format!(
+                    "couldn't parse '{}' into a duration{}",
+                    cli.raw_date, reason
+                ),
             );
-            if let Ok(evry_parse_logfile) = env::var("EVRY_PARSE_ERROR_LOG") {
+            printer.report.decision = Some(printer::Decision::Error);
+            if let Some(evry_parse_logfile) = cli.parse_error_log.as_ref().filter(|_| !cli.dry_run)
+            {
                 let mut logfile = std::fs::OpenOptions::new()
                     .create(true)
                     .append(true)
                     .open(evry_parse_logfile)
-                    .context("Couldn't open EVRY_PARSE_ERROR_LOG")?;
-                writeln!(
-                    logfile,
-                    "Could not parse: {} -{}",
-                    cli.raw_date, cli.tag.name
-                )
-                .context("Couldn't write to logfile")?;
+                    .context(format!("Couldn't open {}", evry_parse_logfile))?;
+                let tag_name = cli.tag.as_ref().map_or("", |tag| tag.name.as_str());
+                writeln!(logfile, "Could not parse: {} -{}", cli.raw_date, tag_name)
+                    .context("Couldn't write to logfile")?;
             }
-            return Ok(1); // fatal error
+            return Ok(Exit::ParseError);
         }
     };
+    let run_every = schedule.every;
+
+    printer.report.duration_ms = Some(run_every);
 
     if matches!(cli.command, Command::Duration) {
         if !cli.debug {
-            println!("{}", run_every / 1000);
+            if cli.format_flag {
+                println!("{}", cli.duration_format.format(run_every));
+            } else {
+                println!("{}", run_every / 1000);
+            }
         } else {
             printer.echo("duration", &format!("{}", run_every));
             printer.echo("duration_seconds", &format!("{}", run_every / 1000));
-            printer.echo("duration_pretty", &utils::describe_ms(run_every));
+            printer.echo("duration_pretty", &cli.duration_format.format(run_every));
         }
-        return Ok(0);
+        return Ok(Exit::Success);
     }
 
+    let tag = cli.tag()?;
+    tag.check_clash()?;
+
+    // if another process is checking this tag it'll update the tag file,
+    // so this exits instead of waiting for it and checking again
+    let _tag_lock = tag.lock()?;
+    // held until evry exits, so another process checking this
+    // tag at the same time waits for the tag file to be updated
+    let _lock = dir_info.lock()?;
+
+    // the job file is read (at most) once, for the window and for strict mode. Its
+    // only parsed for the window if this tag is in it, so a mistake in another job
+    // doesn't stop this tag from running. If this tag is in it but it can't be parsed,
+    // this fails rather than running outside of the window
+    let check_strict = cli.strict && !cli.new && !tag.file_exists();
+    let job_file = cli.job_file(None)?;
+    let job_list = match (cli.window.is_none() || check_strict) && job_file.exists() {
+        true => {
+            let contents = jobs::read_contents(&job_file)?;
+            match check_strict || jobs::lists_tag(&contents, &tag.name) {
+                true => Some(jobs::parse_job_file(&job_file, &contents)?),
+                false => None,
+            }
+        }
+        false => None,
+    };
+
+    // the time of day this can run in, from --window or the job file if this tag is in it
+    schedule.window = match (cli.window, &job_list) {
+        (Some(window), _) => Some(window),
+        (None, Some(jobs)) => jobs
+            .iter()
+            .find(|job| job.tag == tag.name)
+            .and_then(|job| job.schedule.window),
+        (None, None) => None,
+    };
+
     // get current time
     let now = utils::epoch_millis().context("Couldn't get current time")?;
+    let clocks = utils::Clocks {
+        boot: match cli.monotonic {
+            true => utils::BootTime::now(),
+            false => None,
+        },
+        active: match cli.active {
+            true => utils::BootTime::active(),
+            false => None,
+        },
+    };
 
     if cli.debug {
+        if cli.monotonic && clocks.boot.is_none() {
+            printer.echo(
+                "log",
+                "Couldn't read the boot ID or CLOCK_BOOTTIME, measuring with wall time",
+            );
+        }
+        if cli.active && clocks.active.is_none() {
+            printer.echo(
+                "log",
+                "Couldn't read the boot ID or CLOCK_MONOTONIC, measuring with wall time",
+            );
+        }
         printer.echo(
             "log",
             &format!("parsed '{}' into {}ms", cli.raw_date, run_every),
@@ -241,46 +876,255 @@ fn evry(dir_info: file::LocalDir, cli: Args, printer: &mut printer::Printer) ->
             Some(printer::PrinterType::Json),
         );
         printer.print(
-            printer::Message::new("duration_pretty", &utils::describe_ms(run_every)),
+            printer::Message::new("duration_pretty", &cli.duration_format.format(run_every)),
             Some(printer::PrinterType::Json),
         );
+        if let Some(calendar) = schedule.describe(&zone) {
+            printer.echo("log", &format!("Runs {}", calendar));
+        }
+    }
+
+    if check_strict {
+        // in strict mode, new tags have to be created explicitly, or listed in
+        // the job file, so a typo doesn't run the job immediately
+        let jobs = job_list.unwrap_or_default();
+        if !jobs.iter().any(|job| job.tag == tag.name) {
+            let mut known = dir_info.list_tags(None)?;
+            known.extend(jobs.into_iter().map(|job| job.tag));
+            let suggestion = match file::suggest_tag_name(&tag.name, &known) {
+                Some(name) => format!(", did you mean '{}'?", name),
+                None => String::new(),
+            };
+            bail!(
+                "Tag '{}' doesn't exist, pass --new to create it{}",
+                tag.name,
+                suggestion
+            );
+        }
     }
 
-    if !cli.tag.file_exists() {
+    let deferred = match tag.file_exists() {
+        true => None,
+        false => cli.first_run.deferred_last_run(now, run_every),
+    };
+    if let Some(last_run) = deferred {
+        // this is the first time this tag is being run, but the user asked
+        // to wait before running it. Save a last run time so that the tag
+        // is next due after the delay, and exit with a 2 exit code
+        // the clocks aren't saved, since they'd have to be shifted back
+        // by the delay as well, so the first interval uses wall time
+        write_tag(
+            &cli,
+            printer,
+            tag,
+            last_run,
+            run_every,
+            &utils::Clocks::default(),
+        )?;
+        // with a time of day, the first run is the next time that comes around after the delay
+        let next_run = schedule.next_run(last_run, &zone);
+        let delay = next_run.saturating_sub(now);
+        printer.report.decision = Some(printer::Decision::Skip);
+        printer.report.next_run_ms = Some(next_run);
+        printer.report.till_next_ms = Some(delay);
+        if cli.debug {
+            let delay_pretty = cli.duration_format.format(delay);
+            printer.echo(
+                "log",
+                &format!(
+                    "Tag file doesn't exist, first run policy is '{}', deferring the first run by '{}' ({}ms), exiting with code 2",
+                    cli.first_run.describe(),
+                    delay_pretty,
+                    delay
+                ),
+            );
+            printer.print(
+                printer::Message::new("till_next", &format!("{}", delay)),
+                Some(printer::PrinterType::Json),
+            );
+            printer.print(
+                printer::Message::new("till_next_pretty", &delay_pretty),
+                Some(printer::PrinterType::Json),
+            );
+            echo_timestamp(printer, "next_run", next_run);
+        }
+        return Ok(Exit::NotDue);
+    }
+
+    if !tag.file_exists() && !schedule.can_run_at(now, &zone) {
+        // this is the first time this tag is being run, but it can't run
+        // today/outside its window, so wait without creating the tag file
+        let next_run = schedule.next_allowed(now, &zone);
+        let delay = next_run - now;
+        printer.report.decision = Some(printer::Decision::Skip);
+        printer.report.next_run_ms = Some(next_run);
+        printer.report.till_next_ms = Some(delay);
+        if cli.debug {
+            printer.echo(
+                "log",
+                &format!(
+                    "Tag file doesn't exist, but it can't run now ({}), exiting with code 2",
+                    schedule.describe(&zone).unwrap_or_default()
+                ),
+            );
+            printer.print(
+                printer::Message::new("till_next", &format!("{}", delay)),
+                Some(printer::PrinterType::Json),
+            );
+            printer.print(
+                printer::Message::new("till_next_pretty", &cli.duration_format.format(delay)),
+                Some(printer::PrinterType::Json),
+            );
+            echo_timestamp(printer, "next_run", next_run);
+        }
+        return Ok(Exit::NotDue);
+    }
+
+    if !tag.file_exists() {
         // file doesn't exist, this is the first time this tag is being run.
         // save the current milliseconds to the file and exit with a 0 exit code
         if cli.debug {
             printer.echo(
                 "log",
-                "Tag file doesn't exist, creating and exiting with code 0",
+                "Tag file doesn't exist, first run policy is 'run', creating and exiting with code 0",
             );
         }
-        cli.tag.write(now)?;
-        return Ok(0);
+        write_tag(&cli, printer, tag, now, run_every, &clocks)?;
+        let next_run = schedule.next_run(now, &zone);
+        if cli.debug {
+            echo_timestamp(printer, "next_run", next_run);
+        }
+        printer.report.decision = Some(printer::Decision::Run);
+        printer.report.next_run_ms = Some(next_run);
+        printer.report.till_next_ms = Some(next_run - now);
+        return Ok(Exit::Success);
     } else {
         // file exists, read last time this tag was run
-        let last_ran_at = cli.tag.read_epoch_millis()?;
-        if now - last_ran_at > run_every {
+        let state = tag.read_state()?;
+        let mut last_ran_at = state.last_run;
+        let active_elapsed = clocks
+            .active
+            .as_ref()
+            .map(|active| state.active_elapsed(now, active));
+        let boot_elapsed = match (&clocks.boot, state.boot_time()) {
+            (Some(current), Some(saved)) => current.since(&saved),
+            _ => None,
+        };
+        let elapsed = match (active_elapsed, boot_elapsed, now.checked_sub(last_ran_at)) {
+            (Some(elapsed), _, _) => {
+                if cli.debug {
+                    printer.echo(
+                        "log",
+                        &format!(
+                            "The machine has been active for '{}' ({}ms) since the last run",
+                            cli.duration_format.format(elapsed),
+                            elapsed
+                        ),
+                    );
+                }
+                elapsed
+            }
+            (None, Some(elapsed), _) => {
+                // ran in the same boot, so use the clock which isn't
+                // affected by NTP/the user changing the time
+                if cli.debug {
+                    printer.echo(
+                        "log",
+                        &format!(
+                            "Last ran in the same boot, '{}' ({}ms) ago according to CLOCK_BOOTTIME",
+                            cli.duration_format.format(elapsed),
+                            elapsed
+                        ),
+                    );
+                }
+                elapsed
+            }
+            (None, None, Some(elapsed)) => elapsed,
+            (None, None, None) => {
+                // the tag file is in the future, the clock went backwards
+                // or this was synced from a machine with a different time
+                let ahead = last_ran_at - now;
+                printer.echo(
+                    "warning",
+                    &format!(
+                        "Tag '{}' last ran '{}' ({}ms) in the future at {}, clock skew policy is '{}'",
+                        tag.name,
+                        cli.duration_format.format(ahead),
+                        ahead,
+                        utils::rfc3339_local(last_ran_at),
+                        cli.clock_skew.describe()
+                    ),
+                );
+                let (elapsed, last_run) = cli.clock_skew.resolve(&tag.name, last_ran_at, now)?;
+                if last_run != last_ran_at {
+                    write_tag(&cli, printer, tag, last_run, run_every, &clocks)?;
+                    last_ran_at = last_run;
+                }
+                elapsed
+            }
+        };
+        printer.report.last_run_ms = Some(last_ran_at);
+        // when this is due, counting back from now, so the elapsed
+        // time from CLOCK_BOOTTIME/the active time is used
+        let mut next_run = schedule.next_run(now.saturating_sub(elapsed), &zone);
+        let due = match schedule.is_calendar() {
+            true => now >= next_run && schedule.can_run_at(now, &zone),
+            false => elapsed > run_every,
+        };
+        if !due && next_run <= now {
+            // its overdue, but it can't run today/outside its window
+            next_run = schedule.next_allowed(now, &zone);
+        }
+        if due {
             // duration this should be run at has elapsed, run
             if cli.debug {
-                printer.echo("log", &format!("Has been more than '{}' ({}ms) since last succeeded, writing to tag file, exiting with code 0", utils::describe_ms(run_every), run_every));
+                match schedule.describe(&zone) {
+                    Some(calendar) => printer.echo("log", &format!("Reached the next run ({}), writing to tag file, exiting with code 0", calendar)),
+                    None => printer.echo("log", &format!("Has been more than '{}' ({}ms) since last succeeded, writing to tag file, exiting with code 0", cli.duration_format.format(run_every), run_every)),
+                }
             }
             // save current time to tag file
-            cli.tag.write(now)?;
-            return Ok(0);
+            write_tag(&cli, printer, tag, now, run_every, &clocks)?;
+            let next_run = schedule.next_run(now, &zone);
+            if cli.debug {
+                echo_timestamp(printer, "last_run", last_ran_at);
+                echo_timestamp(printer, "next_run", next_run);
+            }
+            printer.report.decision = Some(printer::Decision::Run);
+            printer.report.next_run_ms = Some(next_run);
+            printer.report.till_next_ms = Some(next_run - now);
+            return Ok(Exit::Success);
         } else {
             // this has been run within the specified duration, don't run
+            let till_next_run = next_run - now;
+            printer.report.decision = Some(printer::Decision::Skip);
+            printer.report.next_run_ms = Some(next_run);
+            printer.report.till_next_ms = Some(till_next_run);
+            if let Some(active) = &clocks.active {
+                // save the active time so far, so any time spent
+                // suspended after this isn't counted
+                let mut state = state;
+                state.set_active(Some(active), elapsed);
+                if cli.dry_run {
+                    printer.echo(
+                        "dry_run",
+                        "Dry run, not saving the active time to the tag file",
+                    );
+                } else {
+                    tag.write_state(&state)?;
+                }
+            }
             if cli.debug {
-                printer.echo(
-                    "log",
-                    &format!(
-                        "{} ({}ms) haven't elapsed since last run, exiting with code 1",
-                        utils::describe_ms(run_every),
+                let reason = match schedule.describe(&zone) {
+                    Some(calendar) => format!("Hasn't reached the next run ({})", calendar),
+                    None => format!(
+                        "{} ({}ms) haven't elapsed since last run",
+                        cli.duration_format.format(run_every),
                         run_every
                     ),
-                );
-                let till_next_run = last_ran_at + run_every - now;
-                let till_next_pretty = utils::describe_ms(till_next_run);
+                };
+                printer.echo("log", &format!("{}, exiting with code 2", reason));
+                let till_next_pretty = cli.duration_format.format(till_next_run);
                 printer.echo(
                     "log",
                     &format!(
@@ -296,30 +1140,336 @@ fn evry(dir_info: file::LocalDir, cli: Args, printer: &mut printer::Printer) ->
                     printer::Message::new("till_next_pretty", &till_next_pretty),
                     Some(printer::PrinterType::Json),
                 );
+                echo_timestamp(printer, "last_run", last_ran_at);
+                echo_timestamp(printer, "next_run", next_run);
             }
-            return Ok(2); // exit code 2; expected error, to cause next shell command to not run
+            return Ok(Exit::NotDue); // expected, to cause next shell command to not run
         }
     }
 }
 
-fn main() -> Result<(), Error> {
+/// saves the time this tag ran to its tag file, unless this is a dry run
+fn write_tag(
+    cli: &Args,
+    printer: &mut printer::Printer,
+    tag: &file::Tag,
+    time: u128,
+    interval: u128,
+    clocks: &utils::Clocks,
+) -> Result<(), Error> {
+    if cli.dry_run {
+        printer.echo(
+            "dry_run",
+            &format!(
+                "Dry run, not saving {} to the tag file",
+                utils::rfc3339_local(time)
+            ),
+        );
+        return Ok(());
+    }
+    tag.write(time, interval, clocks)
+}
+
+/// warns about each tag which couldn't be read by a command that checks
+/// every tag, those tags are skipped and the command exits with an error
+fn warn_unreadable(printer: &mut printer::Printer, errors: &[Error]) {
+    for error in errors {
+        printer.echo("warning", &format!("Skipping tag, {:#}", error));
+    }
+}
+
+/// prints an epoch time (milliseconds) as RFC 3339
+/// timestamps, in local time and in UTC
+fn echo_timestamp(printer: &mut printer::Printer, name: &str, ms: u128) {
+    printer.echo(name, &utils::rfc3339_local(ms));
+    printer.echo(&format!("{}_utc", name), &utils::rfc3339_utc(ms));
+}
+
+/// resolves the data directory, parses user input, and runs evry
+fn run(parsed: cli::Parsed, printer: &mut printer::Printer) -> Result<Exit, Error> {
+    // asking for help isn't an error, print it to stdout
+    if let Some(topic) = parsed.help_topic()? {
+        match topic {
+            Some(info) => println!("{}", cli::command_help(info)),
+            None => println!("{}", cli::help(&Exit::table())),
+        }
+        return Ok(Exit::Success);
+    }
     // global application information
-    let dir_info = file::LocalDir::new()?;
-    let cli = Args::parse_args(&dir_info)?;
+    let dir_info = file::LocalDir::new(parsed.value("dir"))?;
+    let cli = Args::parse_args(parsed, &dir_info)?;
+    evry(dir_info, cli, printer)
+}
 
-    let printer_type = if cli.json {
-        printer::PrinterType::Json
-    } else {
-        printer::PrinterType::Stderr
+/// how to print messages, from --json/EVRY_JSON
+fn printer_type(parsed: &Result<cli::Parsed, Error>) -> printer::PrinterType {
+    // --json=ndjson prints each message as its generated,
+    // --json=object prints a single object with typed fields at the end,
+    // any other value saves them and prints a JSON array at the end
+    let json = match parsed {
+        Ok(parsed) => parsed.value("json"),
+        Err(_) => env::var("EVRY_JSON").ok(),
     };
+    match json.as_deref() {
+        Some("ndjson") => printer::PrinterType::Ndjson,
+        Some("object") => printer::PrinterType::Object,
+        Some(_) => printer::PrinterType::Json,
+        None => printer::PrinterType::Stderr,
+    }
+}
+
+/// reports the result of running evry, returning the exit code
+fn finish(result: Result<Exit, Error>, printer: &mut printer::Printer) -> i32 {
+    let result = match result {
+        Ok(exit) => exit as i32,
+        Err(e) => {
+            printer.echo("error", &format!("{:#}", e));
+            printer.report.decision = Some(printer::Decision::Error);
+            let exit = Exit::from_error(&e);
+            if exit == Exit::Usage && printer.is_stderr() {
+                eprintln!("\n{}", cli::help(&Exit::table()));
+            }
+            exit as i32
+        }
+    };
+
+    printer.report.exit_code = result;
+
+    // if user specified JSON, print the blob.
+    // this runs even if evry failed, so the error is always reported
+    printer.flush();
+    result
+}
+
+fn main() {
+    let parsed = cli::parse(env::args().skip(1));
 
     // handles printing/saving messages in case we're in JSON mode
-    let mut printer = printer::Printer::new(printer_type);
+    let mut printer = printer::Printer::new(printer_type(&parsed));
 
     // run 'main' code, saving exit code
-    let result = evry(dir_info, cli, &mut printer)?;
+    let result = parsed.and_then(|parsed| run(parsed, &mut printer));
+    exit(finish(result, &mut printer));
+}
 
-    // if user specified JSON, print the blob
-    printer.flush();
-    exit(result);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// runs evry like `main` does, returning the exit code and what was printed to stdout
+    fn run_evry(args: &[&str]) -> (i32, String) {
+        let out = printer::Captured::default();
+        let parsed = cli::parse(args.iter().map(|arg| arg.to_string()));
+        let mut printer =
+            printer::Printer::with_output(printer_type(&parsed), Box::new(out.clone()));
+        let result = parsed.and_then(|parsed| run(parsed, &mut printer));
+        (finish(result, &mut printer), out.text())
+    }
+
+    /// a data directory for a test, removed when its dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("evry-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn arg(&self) -> String {
+            format!("--dir={}", self.0.display())
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_exit_from_error() {
+        let usage = Error::from(UsageError("Must provide a tag".to_string()));
+        assert_eq!(Exit::from_error(&usage), Exit::Usage);
+        let parse = parser::parse_schedule("1 fortnight").unwrap_err();
+        assert_eq!(Exit::from_error(&parse), Exit::ParseError);
+        let lock = Error::from(file::LockError(PathBuf::from("/data")))
+            .context("Couldn't check tag 'backup'");
+        assert_eq!(Exit::from_error(&lock), Exit::LockContention);
+        let running = Error::from(file::AlreadyRunning("backup".to_string()));
+        assert_eq!(Exit::from_error(&running), Exit::AlreadyRunning);
+        let storage = Error::from(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("Could not create tag file")
+            .context("Couldn't check tag 'backup'");
+        assert_eq!(Exit::from_error(&storage), Exit::StorageError);
+        assert_eq!(
+            Exit::from_error(&anyhow::anyhow!("Tag 'a' has never run")),
+            Exit::Error
+        );
+        // the codes are stable, so they can't change
+        assert_eq!(
+            Exit::table()
+                .iter()
+                .map(|(code, _)| *code)
+                .collect::<Vec<i32>>(),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 10]
+        );
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let dir = TestDir::new("json-errors");
+        assert_eq!(run_evry(&[&dir.arg(), "5", "seconds", "-a"]).0, 0);
+        // 'a' is a tag, so it can't be used as a namespace
+        let (code, out) = run_evry(&[&dir.arg(), "--json=ndjson", "5", "seconds", "-a/b"]);
+        assert_eq!(code, 1);
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let error = lines.last().unwrap();
+        assert_eq!(error["type"], "error");
+        assert!(error["body"].as_str().unwrap().contains("'a' is a tag"));
+
+        let (code, out) = run_evry(&[&dir.arg(), "--json", "5", "seconds", "-a/b"]);
+        assert_eq!(code, 1);
+        let messages: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert!(messages
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|message| message["type"] == "error"));
+
+        // a usage error, since there isn't a tag
+        let (code, out) = run_evry(&[&dir.arg(), "--json=object", "5", "seconds"]);
+        assert_eq!(code, Exit::Usage as i32);
+        let report: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(report["exit_code"], Exit::Usage as i32);
+        assert_eq!(report["decision"], "error");
+        assert!(report["error"].as_str().unwrap().contains("tag name"));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = TestDir::new("dry-run");
+        let dry = |args: &[&str]| {
+            let (code, out) =
+                run_evry(&[&[dir.arg().as_str(), "--json=ndjson", "--dry-run"], args].concat());
+            let dry_run = out.lines().any(|line| line.contains("\"dry_run\""));
+            (code, dry_run)
+        };
+        let tag_file = dir.0.join("data").join("a");
+        // a new tag isn't created
+        assert_eq!(dry(&["5", "seconds", "-a"]), (0, true));
+        assert!(!tag_file.exists());
+
+        // a due tag isn't updated
+        assert_eq!(run_evry(&[&dir.arg(), "5", "seconds", "-a"]).0, 0);
+        fs::write(&tag_file, "1000").unwrap();
+        assert_eq!(dry(&["5", "seconds", "-a"]), (0, true));
+        assert_eq!(fs::read_to_string(&tag_file).unwrap(), "1000");
+
+        // or removed
+        assert_eq!(dry(&["--debug", "reset", "-a"]), (0, true));
+        assert_eq!(fs::read_to_string(&tag_file).unwrap(), "1000");
+        let (_, out) = run_evry(&[
+            &dir.arg(),
+            "--json=ndjson",
+            "--dry-run",
+            "--debug",
+            "reset",
+            "-a",
+        ]);
+        assert!(!out.contains("Removed tag"));
+
+        // the legacy directory isn't moved
+        let legacy = || file::LocalDir {
+            data_dir: dir.0.join("data"),
+            source: file::DirSource::Legacy,
+        };
+        let out = printer::Captured::default();
+        let mut printer =
+            printer::Printer::with_output(printer::PrinterType::Ndjson, Box::new(out.clone()));
+        let parsed =
+            cli::parse(["--dry-run", "migrate"].iter().map(|arg| arg.to_string())).unwrap();
+        let cli = Args::parse_args(parsed, &legacy()).unwrap();
+        assert_eq!(evry(legacy(), cli, &mut printer).unwrap(), Exit::Success);
+        assert!(out.text().contains("Dry run, not moving tags"));
+        assert_eq!(fs::read_to_string(&tag_file).unwrap(), "1000");
+    }
+
+    #[test]
+    fn test_first_run_policy() {
+        let now = 1_000_000;
+        assert_eq!(FirstRun::Run.deferred_last_run(now, 5_000), None);
+        // next due one interval after now
+        assert_eq!(FirstRun::Defer.deferred_last_run(now, 5_000), Some(now));
+        for _ in 0..20 {
+            let last_run = FirstRun::Random.deferred_last_run(now, 5_000).unwrap();
+            assert!(last_run >= now - 5_000 && last_run < now);
+        }
+        // an interval longer than the time since the epoch
+        assert_eq!(FirstRun::Defer.deferred_last_run(1_000, 5_000), Some(1_000));
+    }
+
+    #[test]
+    fn test_clock_skew_policy() {
+        let now = 1_000_000;
+        let future = now + 60_000;
+        assert_eq!(
+            ClockSkew::JustRun.resolve("a", future, now).unwrap(),
+            (0, now)
+        );
+        assert_eq!(
+            ClockSkew::NeverRun.resolve("a", future, now).unwrap(),
+            (u128::MAX, future)
+        );
+        let error = ClockSkew::Error.resolve("a", future, now).unwrap_err();
+        assert!(error.to_string().contains("evry reset -a"));
+    }
+
+    #[test]
+    fn test_job_file_window() {
+        let dir = TestDir::new("job-file-window");
+        fs::create_dir_all(&dir.0).unwrap();
+        let job_file = dir.0.join("jobs");
+        let job_file_arg = format!("--job-file={}", job_file.display());
+        let run = |args: &[&str]| {
+            run_evry(&[&[dir.arg().as_str(), job_file_arg.as_str()], args].concat())
+        };
+
+        // a window which opens in 2 hours, so the tag can't run now
+        let now = utils::epoch_millis().unwrap();
+        let local = (now / 1000) as i64 + utils::local_offset_seconds((now / 1000) as i64);
+        let hour = local.rem_euclid(utils::SECONDS_PER_DAY) / 3600;
+        fs::write(
+            &job_file,
+            format!(
+                "[windowed]\nevery = 1 day\nwindow = {:02}:00-{:02}:00\n",
+                (hour + 2) % 24,
+                (hour + 3) % 24
+            ),
+        )
+        .unwrap();
+        assert_eq!(run(&["1", "day", "-windowed"]).0, Exit::NotDue as i32);
+        assert_eq!(run(&["1", "day", "-other"]).0, 0);
+
+        // a mistake in another job doesn't stop tags which aren't in the job file from running
+        fs::write(
+            &job_file,
+            "[broken]\nevery = 1 fortnight\n[windowed]\nevery = 1 day\nwindow = 01:00-05:00\n",
+        )
+        .unwrap();
+        assert_eq!(run(&["1", "day", "-unlisted"]).0, 0);
+        // but a tag which is in it doesn't run without its window
+        let (code, out) = run(&["--json=ndjson", "1", "day", "-windowed"]);
+        assert_eq!(code, Exit::ParseError as i32);
+        assert!(out.contains("Could not parse job file"));
+        // unless its passed explicitly
+        assert_eq!(run(&["--window=00:00-23:59", "1", "day", "-windowed"]).0, 0);
+        // but strict mode needs the whole job file
+        let (code, out) = run(&["--json=ndjson", "--strict", "1", "day", "-unknown"]);
+        assert_ne!(code, 0);
+        assert!(out.contains("Could not parse job file"));
+    }
 }
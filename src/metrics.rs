@@ -0,0 +1,201 @@
+//! Exports metrics about every tag for the
+//! [node_exporter textfile collector](https://github.com/prometheus/node_exporter#textfile-collector)
+
+use anyhow::{Context, Error, Result};
+use std::{
+    fs::{rename, File},
+    io::Write,
+    path::Path,
+};
+
+use crate::file::{self, LocalDir, Tag, TagState};
+
+/// (name, help text, how to get the value from a tag file) for each gauge
+#[doc(hidden)]
+type Gauge = (&'static str, &'static str, fn(&TagState) -> Option<String>);
+
+/// the name and saved state of each tag
+type TagStates = Vec<(String, TagState)>;
+
+/// converts epoch milliseconds to (fractional) seconds
+fn seconds(ms: u128) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+#[doc(hidden)]
+const GAUGES: [Gauge; 4] = [
+    (
+        "evry_last_run_timestamp_seconds",
+        "When the tag last ran, as a unix timestamp",
+        |state| Some(seconds(state.last_run)),
+    ),
+    (
+        "evry_last_success_timestamp_seconds",
+        "When the tag last exited successfully (saved with evry outcome), as a unix timestamp",
+        |state| state.get::<u128>(file::LAST_SUCCESS_KEY).map(seconds),
+    ),
+    (
+        "evry_interval_seconds",
+        "The interval the tag was last run with",
+        |state| state.get::<u128>(file::INTERVAL_KEY).map(seconds),
+    ),
+    (
+        "evry_last_exit_code",
+        "Exit code of the last run (saved with evry outcome)",
+        |state| {
+            state
+                .get::<i32>(file::EXIT_CODE_KEY)
+                .map(|code| code.to_string())
+        },
+    ),
+];
+
+/// escapes a label value, as described in the text exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// renders the metrics for the given (tag name, tag state) pairs
+pub fn render(tags: &[(String, TagState)]) -> String {
+    let mut out = String::new();
+    for (name, help, value) in GAUGES {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n",
+            name, help, name
+        ));
+        for (tag, state) in tags {
+            if let Some(value) = value(state) {
+                out.push_str(&format!(
+                    "{}{{tag=\"{}\"}} {}\n",
+                    name,
+                    escape_label(tag),
+                    value
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// reads every tag file in the data directory.
+///
+/// Tags which can't be read (or have an invalid name) are skipped, and their errors are returned
+pub fn read_tags(local_dir: &LocalDir) -> Result<(TagStates, Vec<Error>), Error> {
+    let mut tags = vec![];
+    let mut errors = vec![];
+    for name in local_dir.list_tags(None)? {
+        match Tag::new(name.clone(), local_dir).and_then(|tag| tag.read_state()) {
+            Ok(state) => tags.push((name, state)),
+            Err(e) => errors.push(e),
+        }
+    }
+    Ok((tags, errors))
+}
+
+/// writes the file atomically, by writing to a temporary file in the same
+/// directory and renaming it, so the collector never reads a partial file
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), Error> {
+    let filename = path
+        .file_name()
+        .context(format!("{} isn't a file", path.display()))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        filename.to_string_lossy(),
+        std::process::id()
+    ));
+    let mut fp =
+        File::create(&tmp_path).context(format!("Could not create {}", tmp_path.display()))?;
+    fp.write_all(contents.as_bytes())
+        .and_then(|_| fp.sync_all())
+        .context(format!("Could not write to {}", tmp_path.display()))?;
+    rename(&tmp_path, path).context(format!(
+        "Could not move {} to {}",
+        tmp_path.display(),
+        path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("evry-test-metrics-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("evry.prom");
+        write_atomic(&path, "first\n").unwrap();
+        write_atomic(&path, "second\n").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "second\n");
+        // the temporary file was renamed
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        assert!(write_atomic(&dir.join("missing").join("evry.prom"), "").is_err());
+        assert!(write_atomic(Path::new("/"), "").is_err());
+
+        let local_dir = LocalDir {
+            data_dir: dir.join("data"),
+            ..Default::default()
+        };
+        let mut state = TagState {
+            last_run: 1697592310021,
+            ..Default::default()
+        };
+        state.set(file::EXIT_CODE_KEY, 1);
+        for name in ["backup/photos", "scrapesite"] {
+            Tag::new(name.to_string(), &local_dir)
+                .unwrap()
+                .write_state(&state)
+                .unwrap();
+        }
+        let (tags, errors) = read_tags(&local_dir).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(
+            tags,
+            vec![
+                ("backup/photos".to_string(), state.clone()),
+                ("scrapesite".to_string(), state),
+            ]
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_render() {
+        let mut state = TagState {
+            last_run: 1697592310021,
+            ..Default::default()
+        };
+        state.set(file::INTERVAL_KEY, 86400000);
+        state.set(file::EXIT_CODE_KEY, 0);
+        state.set(file::LAST_SUCCESS_KEY, 1697592315000u128);
+        let old = TagState {
+            last_run: 1000,
+            ..Default::default()
+        };
+        let tags = vec![
+            ("backup/photos".to_string(), state),
+            ("we\"ird\\".to_string(), old),
+        ];
+        assert_eq!(
+            render(&tags),
+            "# HELP evry_last_run_timestamp_seconds When the tag last ran, as a unix timestamp
+# TYPE evry_last_run_timestamp_seconds gauge
+evry_last_run_timestamp_seconds{tag=\"backup/photos\"} 1697592310.021
+evry_last_run_timestamp_seconds{tag=\"we\\\"ird\\\\\"} 1.000
+# HELP evry_last_success_timestamp_seconds When the tag last exited successfully (saved with evry outcome), as a unix timestamp
+# TYPE evry_last_success_timestamp_seconds gauge
+evry_last_success_timestamp_seconds{tag=\"backup/photos\"} 1697592315.000
+# HELP evry_interval_seconds The interval the tag was last run with
+# TYPE evry_interval_seconds gauge
+evry_interval_seconds{tag=\"backup/photos\"} 86400.000
+# HELP evry_last_exit_code Exit code of the last run (saved with evry outcome)
+# TYPE evry_last_exit_code gauge
+evry_last_exit_code{tag=\"backup/photos\"} 0
+"
+        );
+    }
+}
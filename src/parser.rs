@@ -1,6 +1,7 @@
 //! Code to parse the duration grammar into milliseconds
 //!
-//! `parse_time` takes a string, and returns the duration that represents in milliseconds
+//! `parse_schedule` takes a string, and returns the duration that represents in milliseconds,
+//! along with the days of the week/time of day it's limited to
 //!
 //! Examples of acceptable input:
 //!
@@ -11,14 +12,19 @@
 //! * `5weeks, 2weeks` (is additive, so this would result in 7 weeks)
 //! * `60sec 2weeks` (order doesn't matter)
 //!
+//! `parse_schedule` also accepts a time of day (and timezone) to run at, like
+//! `1 day at 02:30 in Europe/Berlin`
+//!
 //! See [the grammar](https://github.com/seanbreckenridge/evry/blob/master/src/time.pest) for all
 //! possible time durations.
 
 // for pest macros
 #![allow(clippy::upper_case_acronyms)]
 
-use anyhow::{self, Result};
-use pest::Parser;
+use anyhow::{self, bail, Context, Result};
+use pest::{iterators::Pair, Parser};
+
+use crate::schedule::{Schedule, Weekdays};
 
 /// The pest.rs Parser
 #[derive(Parser)]
@@ -26,72 +32,46 @@ use pest::Parser;
 pub struct TimeParser;
 
 #[doc(hidden)]
-const YEAR_MILLIS: u128 = 31556952000;
+pub const YEAR_MILLIS: u128 = 31556952000;
 #[doc(hidden)]
-const MONTH_MILLIS: u128 = 2592000000;
+pub const MONTH_MILLIS: u128 = 2592000000;
 #[doc(hidden)]
-const WEEK_MILLIS: u128 = 604800000;
+pub const WEEK_MILLIS: u128 = 604800000;
 #[doc(hidden)]
-const DAY_MILLIS: u128 = 86400000;
+pub const DAY_MILLIS: u128 = 86400000;
 #[doc(hidden)]
-const HOUR_MILLIS: u128 = 3600000;
+pub const HOUR_MILLIS: u128 = 3600000;
 #[doc(hidden)]
-const MINUTE_MILLIS: u128 = 60000;
+pub const MINUTE_MILLIS: u128 = 60000;
 #[doc(hidden)]
-const SECOND_MILLIS: u128 = 1000;
+pub const SECOND_MILLIS: u128 = 1000;
 
-/// uses macros to parse the pest.rs grammar into a duration (ms)
-///
-/// This parses the string into milliseconds.
-pub fn parse_time(unparsed_input: &str) -> Result<u128, anyhow::Error> {
-    let mut parsed_file = TimeParser::parse(Rule::file, unparsed_input)?;
+/// parses durations which can be limited to some days of the week and anchored
+/// to a time of day, like `2 business days`, `1 day on mon,wed,fri`, `1 day at 02:30`
+/// or `2 days at 18:00 in Europe/Berlin`
+pub fn parse_schedule(unparsed_input: &str) -> Result<Schedule, anyhow::Error> {
+    let mut parsed_file = TimeParser::parse(Rule::schedule, unparsed_input)?;
 
-    let mut total_millis: u128 = 0;
+    let mut schedule = Schedule::default();
 
-    // unwrap Rule::file, can't fail
+    // unwrap Rule::schedule, can't fail
     for line in parsed_file.next().unwrap().into_inner() {
         match line.as_rule() {
             Rule::durations => {
-                // Pair { durations: [....] inner: [number, time unit] }
-                for durations_expr in line.into_inner() {
-                    //if debug {
-                    //    println!("{:?}", durations_expr);
-                    //}
-                    let mut durations_inner = durations_expr.into_inner();
-                    let quantity: u128 = durations_inner
-                        .next() // item from inner rules
-                        .unwrap()
-                        .as_str() // numeric string, e.g. "3_000", "5"
-                        .trim() // remove whitespace
-                        .parse()
-                        .expect("could not parse input into an integer");
-                    let unit_str = durations_inner.next().unwrap();
-                    // unwrap duration into string, parse again against Rule::singular,
-                    // which doesn't consume the possible 's' from Rule::plural
-                    // This looks a bit dangerous but its fine since pest is handling
-                    // the erroneous input, we're just traversing the AST
-                    let unit_millis: u128 =
-                        match TimeParser::parse(Rule::singular, unit_str.as_str())
-                            .ok() // parse result
-                            .unwrap()
-                            .next() // surrounding (singular/plural pair)
-                            .unwrap()
-                            .into_inner() // inner (unit specific) rules
-                            .next() // first item (only item, the Rule::(singular) variant)
-                            .unwrap()
-                            .as_rule()
-                        {
-                            Rule::year => YEAR_MILLIS,
-                            Rule::month => MONTH_MILLIS,
-                            Rule::week => WEEK_MILLIS,
-                            Rule::day => DAY_MILLIS,
-                            Rule::hour => HOUR_MILLIS,
-                            Rule::minute => MINUTE_MILLIS,
-                            Rule::second => SECOND_MILLIS,
-                            _ => unreachable!(),
-                        };
-                    // add the parsed duration to milliseconds
-                    total_millis += unit_millis * quantity;
+                (schedule.every, schedule.business_days) = durations_millis(line);
+            }
+            Rule::on => {
+                schedule.days = Some(Weekdays::from_names(
+                    line.into_inner().map(|day_of_week| day_of_week.as_str()),
+                )?);
+            }
+            Rule::at => {
+                for part in line.into_inner() {
+                    match part.as_rule() {
+                        Rule::time_of_day => schedule.at = Some(parse_time_of_day(part.as_str())?),
+                        Rule::timezone => schedule.timezone = Some(part.as_str().to_string()),
+                        _ => unreachable!(),
+                    }
                 }
             }
             // remove EOI
@@ -99,5 +79,120 @@ pub fn parse_time(unparsed_input: &str) -> Result<u128, anyhow::Error> {
             _ => unreachable!(),
         };
     }
-    Ok(total_millis)
+    // the time of day comes around once a day, so it can't run more often than that
+    if schedule.at.is_some() && schedule.every < DAY_MILLIS {
+        bail!("A time of day needs a duration of at least a day, like '1 day at 02:30'");
+    }
+    Ok(schedule)
+}
+
+/// parses a time of day like `02:30` into seconds after midnight
+pub fn parse_time_of_day(time: &str) -> Result<u32, anyhow::Error> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+        })
+        .context(format!("Could not parse time of day '{}'", time))?;
+    if hours > 23 || minutes > 59 {
+        bail!(
+            "'{}' isn't a valid time of day, expected 00:00 to 23:59",
+            time
+        );
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+/// adds up the milliseconds in a Rule::durations pair, and
+/// how many business days (included as a day each) there were
+fn durations_millis(durations: Pair<Rule>) -> (u128, u128) {
+    let mut total_millis: u128 = 0;
+    let mut business_days: u128 = 0;
+    // Pair { durations: [....] inner: [number, time unit] }
+    for durations_expr in durations.into_inner() {
+        //if debug {
+        //    println!("{:?}", durations_expr);
+        //}
+        let mut durations_inner = durations_expr.into_inner();
+        let quantity: u128 = durations_inner
+            .next() // item from inner rules
+            .unwrap()
+            .as_str() // numeric string, e.g. "3_000", "5"
+            .trim() // remove whitespace
+            .parse()
+            .expect("could not parse input into an integer");
+        let unit_str = durations_inner.next().unwrap();
+        // unwrap duration into string, parse again against Rule::singular,
+        // which doesn't consume the possible 's' from Rule::plural
+        // This looks a bit dangerous but its fine since pest is handling
+        // the erroneous input, we're just traversing the AST
+        let unit_millis: u128 = match TimeParser::parse(Rule::singular, unit_str.as_str())
+            .ok() // parse result
+            .unwrap()
+            .next() // surrounding (singular/plural pair)
+            .unwrap()
+            .into_inner() // inner (unit specific) rules
+            .next() // first item (only item, the Rule::(singular) variant)
+            .unwrap()
+            .as_rule()
+        {
+            Rule::business_day => {
+                business_days += quantity;
+                DAY_MILLIS
+            }
+            Rule::year => YEAR_MILLIS,
+            Rule::month => MONTH_MILLIS,
+            Rule::week => WEEK_MILLIS,
+            Rule::day => DAY_MILLIS,
+            Rule::hour => HOUR_MILLIS,
+            Rule::minute => MINUTE_MILLIS,
+            Rule::second => SECOND_MILLIS,
+            _ => unreachable!(),
+        };
+        // add the parsed duration to milliseconds
+        total_millis += unit_millis * quantity;
+    }
+    (total_millis, business_days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = parse_schedule("2 days at 02:30 in Europe/Berlin").unwrap();
+        assert_eq!(schedule.every, 2 * DAY_MILLIS);
+        assert_eq!(schedule.at, Some(2 * 3600 + 30 * 60));
+        assert_eq!(schedule.timezone.as_deref(), Some("Europe/Berlin"));
+
+        let schedule = parse_schedule("1d at 9:05").unwrap();
+        assert_eq!(schedule.at, Some(9 * 3600 + 5 * 60));
+        assert_eq!(schedule.timezone, None);
+
+        let schedule = parse_schedule("5 hours, 2 min").unwrap();
+        assert_eq!(schedule.every, 5 * HOUR_MILLIS + 2 * MINUTE_MILLIS);
+        assert_eq!(schedule.at, None);
+
+        let schedule =
+            parse_schedule("2 business days, 3 hours on mon,Wednesday at 09:00").unwrap();
+        assert_eq!(schedule.every, 2 * DAY_MILLIS + 3 * HOUR_MILLIS);
+        assert_eq!(schedule.business_days, 2);
+        assert_eq!(schedule.days.unwrap().describe(), "mon,wed");
+        assert_eq!(schedule.at, Some(9 * 3600));
+        let schedule = parse_schedule("1 weekday at 09:00 on fri").unwrap();
+        assert_eq!(schedule.business_days, 1);
+        assert_eq!(schedule.days.unwrap().describe(), "fri");
+        assert_eq!(
+            parse_schedule("1 weekday").unwrap(),
+            parse_schedule("1 business day").unwrap()
+        );
+
+        assert!(parse_schedule("1d on").is_err());
+        assert!(parse_schedule("1d on mon on tue").is_err());
+        assert_eq!(parse_schedule("2 weekdays").unwrap().business_days, 2);
+        assert!(parse_schedule("1d at 24:00").is_err());
+        assert!(parse_schedule("12 hours at 02:30").is_err());
+        assert!(parse_schedule("1d in Europe/Berlin").is_err());
+    }
 }
@@ -4,11 +4,76 @@
 /// to print more messages to Json since the user
 /// may want to parse specific parts of the logs
 use serde::Serialize;
+use std::io::{self, Write};
 
 #[derive(PartialEq)]
 pub enum PrinterType {
     Stderr,
+    /// saves messages and prints a JSON array on flush
     Json,
+    /// prints each message as a JSON object on its own line, as its generated
+    Ndjson,
+    /// ignores messages, prints the typed `Report` on flush
+    Object,
+}
+
+/// version of the `Report` JSON shape; bumped when
+/// fields are removed or change meaning
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// what evry decided to do with the job
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    /// the duration had elapsed (or this is a new tag), exit successfully
+    Run,
+    /// the job has run within the duration, exit unsuccessfully
+    Skip,
+    /// something went wrong, see `Report.error`
+    Error,
+}
+
+/// A single JSON object describing the result of running evry,
+/// as opposed to the list of `Message`s
+///
+/// Fields which don't apply to the command which was run are `null`
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub schema_version: u32,
+    /// name of the tag
+    pub tag: Option<String>,
+    pub decision: Option<Decision>,
+    pub exit_code: i32,
+    /// the parsed duration
+    pub duration_ms: Option<u128>,
+    /// when this tag last ran (before this invocation), as epoch milliseconds.
+    /// `null` if this is a new tag
+    pub last_run_ms: Option<u128>,
+    /// when this tag will next be able to run, as epoch milliseconds
+    pub next_run_ms: Option<u128>,
+    /// milliseconds until `next_run_ms`
+    pub till_next_ms: Option<u128>,
+    /// if this was a dry run, so the tag file wasn't written to
+    pub dry_run: bool,
+    /// set if evry failed
+    pub error: Option<String>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            tag: None,
+            decision: None,
+            exit_code: 0,
+            duration_ms: None,
+            last_run_ms: None,
+            next_run_ms: None,
+            till_next_ms: None,
+            dry_run: false,
+            error: None,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -32,24 +97,55 @@ impl Message {
     }
 }
 
+/// A structured item (e.g. the status of a job) in ndjson output, which has
+/// a `type` like the messages around it so the lines can be told apart
+#[derive(Serialize)]
+struct Item<'a, T> {
+    r#type: &'a str,
+    item: &'a T,
+}
+
 pub struct Printer {
     /// how to print these messages
     printer_type: PrinterType,
     /// messages to print
     messages: Vec<Message>,
+    /// typed summary, filled in as evry runs. Only printed for `PrinterType::Object`
+    pub report: Report,
+    /// output for commands which describe multiple tags (e.g. `evry status`),
+    /// printed instead of the messages/report
+    payload: Option<String>,
+    /// where JSON is written to, stdout unless this is a test
+    out: Box<dyn Write>,
 }
 
 impl Printer {
     pub fn new(printer_type: PrinterType) -> Self {
+        Self::with_output(printer_type, Box::new(io::stdout()))
+    }
+
+    /// creates a printer which writes JSON to `out` instead of stdout
+    pub fn with_output(printer_type: PrinterType, out: Box<dyn Write>) -> Self {
         Self {
             printer_type,
             messages: vec![],
+            report: Report::default(),
+            payload: None,
+            out,
         }
     }
 
+    /// writes a line of JSON to the output
+    fn println(&mut self, line: &str) {
+        // like println!, but there's nothing to do if stdout is closed
+        let _ = writeln!(self.out, "{}", line);
+    }
+
     /// Print the message (or save it, depending on the printer_type)
     pub fn print(&mut self, message: Message, only: Option<PrinterType>) {
         let allowed = match only {
+            // JSON-only messages are printed in either of the JSON formats
+            Some(PrinterType::Json) => self.printer_type != PrinterType::Stderr,
             Some(ptype) => self.printer_type == ptype,
             None => true,
         };
@@ -57,6 +153,36 @@ impl Printer {
             match self.printer_type {
                 PrinterType::Stderr => eprintln!("{}", message.intersperse(":")),
                 PrinterType::Json => self.messages.push(message),
+                PrinterType::Ndjson => self.println(&serde_json::to_string(&message).unwrap()),
+                // everything else is set on the report directly
+                PrinterType::Object => {
+                    if message.r#type == "error" {
+                        self.report.error = Some(message.body);
+                    }
+                }
+            }
+        }
+    }
+
+    /// whether or not messages are being printed to stderr, as opposed to JSON
+    pub fn is_stderr(&self) -> bool {
+        self.printer_type == PrinterType::Stderr
+    }
+
+    /// Save structured output, which is printed instead of the messages (or report)
+    /// when flushed. Ndjson prints each item immediately, like `{"type":"<type>","item":{..}}`
+    /// so they can be told apart from the messages. This does nothing for Stderr
+    pub fn output<T: Serialize>(&mut self, r#type: &str, items: &[T]) {
+        match self.printer_type {
+            PrinterType::Stderr => (),
+            PrinterType::Ndjson => {
+                for item in items {
+                    let line = serde_json::to_string(&Item { r#type, item }).unwrap();
+                    self.println(&line);
+                }
+            }
+            PrinterType::Json | PrinterType::Object => {
+                self.payload = Some(serde_json::to_string(items).unwrap())
             }
         }
     }
@@ -67,17 +193,49 @@ impl Printer {
         self.print(Message::new(r#type, body), None)
     }
 
-    /// serialize the messages as JSON
+    /// serialize the messages (or the report) as JSON
     fn serialize(&self) -> String {
-        serde_json::to_string(&self.messages).unwrap()
+        if let Some(payload) = &self.payload {
+            return payload.clone();
+        }
+        match self.printer_type {
+            PrinterType::Object => serde_json::to_string(&self.report).unwrap(),
+            _ => serde_json::to_string(&self.messages).unwrap(),
+        }
     }
 
     /// Finalize anything before the program ends. If the printer_type
     /// was JSON, this would serialize and print all the messages
-    pub fn flush(&self) {
-        if self.printer_type == PrinterType::Json {
-            println!("{}", self.serialize())
+    /// (Ndjson messages have already been printed)
+    pub fn flush(&mut self) {
+        if matches!(self.printer_type, PrinterType::Json | PrinterType::Object) {
+            let serialized = self.serialize();
+            self.println(&serialized);
         }
+        let _ = self.out.flush();
+    }
+}
+
+/// output written by a printer, for tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Captured(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
@@ -106,4 +264,36 @@ mod tests {
         );
         assert_eq!(p.serialize(), "[{\"type\":\"data dir\",\"body\":\"~/.local/share/evry/data\"},{\"type\":\"tag name\",\"body\":\"this is tag name\"},{\"type\":\"status\",\"body\":\"something bad happened\"}]");
     }
+
+    #[test]
+    fn test_ndjson_output() {
+        let out = Captured::default();
+        let mut p = Printer::with_output(PrinterType::Ndjson, Box::new(out.clone()));
+        p.echo("log", "checking jobs");
+        p.output("status", &[1, 2]);
+        p.flush();
+        assert_eq!(
+            out.text(),
+            "{\"type\":\"log\",\"body\":\"checking jobs\"}
+{\"type\":\"status\",\"item\":1}
+{\"type\":\"status\",\"item\":2}
+"
+        );
+    }
+
+    #[test]
+    fn test_report_serialize() {
+        let mut p = Printer::new(PrinterType::Object);
+        p.echo("log", "ignored");
+        p.report.tag = Some("backup".to_string());
+        p.report.decision = Some(Decision::Skip);
+        p.report.exit_code = 2;
+        p.report.duration_ms = Some(7200000);
+        p.report.last_run_ms = Some(1000);
+        p.report.next_run_ms = Some(7201000);
+        p.report.till_next_ms = Some(7200000);
+        assert_eq!(p.serialize(), "{\"schema_version\":1,\"tag\":\"backup\",\"decision\":\"skip\",\"exit_code\":2,\"duration_ms\":7200000,\"last_run_ms\":1000,\"next_run_ms\":7201000,\"till_next_ms\":7200000,\"dry_run\":false,\"error\":null}");
+        p.echo("error", "something bad happened");
+        assert_eq!(p.report.error.as_deref(), Some("something bad happened"));
+    }
 }
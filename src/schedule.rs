@@ -0,0 +1,600 @@
+//! Works out when a tag is next due, from a parsed duration which can count
+//! business days, be limited to some days of the week, or be anchored to
+//! a time of day, like `1 day on mon,wed,fri at 02:30 in Europe/Berlin`, and
+//! which can be restricted to a window of time each day, like `01:00-05:00`
+
+use anyhow::{bail, Context, Error, Result};
+use std::str::FromStr;
+
+use crate::{
+    parser::{parse_time_of_day, DAY_MILLIS},
+    tz::Zone,
+    utils::{weekday, SECONDS_PER_DAY},
+};
+
+/// abbreviations for the days of the week, starting with Sunday
+pub const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// the full names of the days of the week, starting with Sunday
+#[doc(hidden)]
+const FULL_DAY_NAMES: [&str; 7] = [
+    "sunday",
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+];
+
+/// if some days since the epoch is a business day, Monday to Friday
+fn is_business_day(day: i64) -> bool {
+    (1..=5).contains(&weekday(day))
+}
+
+/// the local day (days since the epoch) and seconds into that day for some epoch milliseconds
+fn local_day(zone: &Zone, ms: i128) -> (i64, i64) {
+    let local = zone.to_local(ms.div_euclid(1000) as i64);
+    (
+        local.div_euclid(SECONDS_PER_DAY),
+        local.rem_euclid(SECONDS_PER_DAY),
+    )
+}
+
+/// epoch milliseconds for some seconds into a local day
+fn utc_millis(zone: &Zone, day: i64, seconds: i64) -> i128 {
+    zone.to_utc(day * SECONDS_PER_DAY + seconds) as i128 * 1000
+}
+
+/// The days of the week a schedule can run on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    /// parses day names like `mon` or `monday`, which are case insensitive
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, Error> {
+        let mut days = 0;
+        for name in names {
+            let name = name.to_lowercase();
+            let day = (0..7).find(|day| DAY_NAMES[*day] == name || FULL_DAY_NAMES[*day] == name);
+            match day {
+                Some(day) => days |= 1 << day,
+                None => bail!("Unknown day of the week '{}'", name),
+            }
+        }
+        if days == 0 {
+            bail!("Expected at least one day of the week");
+        }
+        Ok(Self(days))
+    }
+
+    /// if this includes a day of the week (0 is Sunday)
+    pub fn contains(&self, weekday: usize) -> bool {
+        self.0 & (1 << weekday) != 0
+    }
+
+    /// the days starting with Monday, like `mon,wed,fri`
+    pub fn describe(&self) -> String {
+        [1, 2, 3, 4, 5, 6, 0]
+            .into_iter()
+            .filter(|day| self.contains(*day))
+            .map(|day| DAY_NAMES[day])
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+}
+
+/// A time of day a tag is allowed to run in, like `01:00-05:00`. If it
+/// ends before it starts, it wraps past midnight, like `22:00-02:00`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    /// seconds after midnight the window opens at
+    pub start: u32,
+    /// seconds after midnight the window closes at
+    pub end: u32,
+}
+
+impl Window {
+    /// if some seconds after midnight are inside the window
+    pub fn contains(&self, seconds: u32) -> bool {
+        match self.start <= self.end {
+            true => self.start <= seconds && seconds < self.end,
+            false => seconds >= self.start || seconds < self.end,
+        }
+    }
+
+    /// describes the window, like `01:00-05:00`
+    pub fn describe(&self) -> String {
+        format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 3600,
+            (self.start % 3600) / 60,
+            self.end / 3600,
+            (self.end % 3600) / 60
+        )
+    }
+}
+
+impl FromStr for Window {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .context(format!("Expected a window like '01:00-05:00', got '{}'", s))?;
+        let window = Self {
+            start: parse_time_of_day(start.trim())?,
+            end: parse_time_of_day(end.trim())?,
+        };
+        if window.start == window.end {
+            bail!("The window '{}' starts and ends at the same time", s);
+        }
+        Ok(window)
+    }
+}
+
+/// When a tag should run, parsed from the duration the user passed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Schedule {
+    /// how long to wait between runs, in milliseconds (business days count as a day)
+    pub every: u128,
+    /// how many of the days in `every` are business days, which skip weekends
+    pub business_days: u128,
+    /// the days of the week this can run on, every day if `None`
+    pub days: Option<Weekdays>,
+    /// the time of day (seconds after midnight) to run at, if the schedule is anchored
+    pub at: Option<u32>,
+    /// the IANA timezone `at` (and the window) is in, like Europe/Berlin. The local timezone if `None`
+    pub timezone: Option<String>,
+    /// the time of day this is allowed to run in, from --window or the job file
+    pub window: Option<Window>,
+}
+
+impl Schedule {
+    /// if this depends on the calendar, rather than just the time since the tag last ran
+    pub fn is_calendar(&self) -> bool {
+        self.business_days > 0 || self.days.is_some() || self.at.is_some() || self.window.is_some()
+    }
+
+    /// if this is allowed to run on a local day (days since the epoch). Business
+    /// days only run Monday to Friday, unless the days were listed with `on`
+    fn allows(&self, day: i64) -> bool {
+        match self.days {
+            Some(days) => days.contains(weekday(day)),
+            None if self.business_days > 0 => is_business_day(day),
+            None => true,
+        }
+    }
+
+    /// the first time at or after `time` (epoch milliseconds) on a day
+    /// this can run on, and inside the window if there is one
+    fn allowed_from(&self, time: i128, zone: &Zone) -> i128 {
+        if self.days.is_none() && self.business_days == 0 && self.window.is_none() {
+            return time;
+        }
+        let (today, seconds) = local_day(zone, time);
+        for day in (today..today + 8).filter(|day| self.allows(*day)) {
+            let window = match self.window {
+                Some(window) => window,
+                None if day == today => return time,
+                // the next day it can run on, at the time of day if there is one
+                None => return utc_millis(zone, day, self.at.unwrap_or(0) as i64),
+            };
+            if day == today && window.contains(seconds as u32) {
+                return time;
+            }
+            // a window which wraps past midnight is already open at the start of the day
+            let opens = match day != today && window.contains(0) {
+                true => 0,
+                false => window.start,
+            };
+            let opens_at = utc_millis(zone, day, opens as i64);
+            if opens_at > time {
+                return opens_at;
+            }
+        }
+        time
+    }
+
+    /// the first time at or after `time` (epoch milliseconds) this can run, e.g. if a
+    /// tag is overdue, but it isn't one of the days it can run on or its outside the window
+    pub fn next_allowed(&self, time: u128, zone: &Zone) -> u128 {
+        self.allowed_from(time as i128, zone).max(0) as u128
+    }
+
+    /// if this can run at some time (epoch milliseconds), once its due
+    pub fn can_run_at(&self, time: u128, zone: &Zone) -> bool {
+        self.next_allowed(time, zone) == time
+    }
+
+    /// when a tag which last ran at `last_run` (epoch milliseconds) is next due
+    ///
+    /// Without a time of day, thats one interval after it last ran, where business
+    /// days skip over weekends. If that's on a day it isn't allowed to run on, its
+    /// the start of the next day it can run on. With a time of day, its the first
+    /// time that comes around (on a day it can run on) once all but one day of the
+    /// interval has passed, so `2d at 02:30` runs at 02:30 every other day. See
+    /// `Zone::to_utc` for what happens when 02:30 is skipped or repeated because of DST.
+    /// If that's outside the window, its when the window next opens
+    pub fn next_run(&self, last_run: u128, zone: &Zone) -> u128 {
+        let mut after = last_run as i128;
+        if self.business_days > 0 {
+            // count business days on the calendar, at the same time of day
+            let (mut day, seconds) = local_day(zone, after);
+            let mut counted = 0;
+            while counted < self.business_days {
+                day += 1;
+                if is_business_day(day) {
+                    counted += 1;
+                }
+            }
+            after = utc_millis(zone, day, seconds) + after.rem_euclid(1000);
+        }
+        after += self.every.saturating_sub(self.business_days * DAY_MILLIS) as i128;
+        let next = match self.at {
+            None => after,
+            Some(at) => {
+                let after = after - DAY_MILLIS as i128;
+                let (day, _) = local_day(zone, after);
+                (day..day + 9)
+                    .filter(|day| self.allows(*day))
+                    .map(|day| utc_millis(zone, day, at as i64))
+                    .find(|next| *next > after)
+                    .unwrap_or(after)
+            }
+        };
+        self.allowed_from(next, zone).max(0) as u128
+    }
+
+    /// describes the calendar parts of the schedule, like
+    /// `every 2 business days on mon,wed at 02:30 in Europe/Berlin`
+    /// or `between 01:00-05:00 in local time`
+    pub fn describe(&self, zone: &Zone) -> Option<String> {
+        let mut parts = vec![];
+        match self.business_days {
+            0 => (),
+            1 => parts.push("every business day".to_string()),
+            days => parts.push(format!("every {} business days", days)),
+        }
+        if let Some(days) = self.days {
+            parts.push(format!("on {}", days.describe()));
+        }
+        if let Some(at) = self.at {
+            parts.push(format!(
+                "at {:02}:{:02} in {}",
+                at / 3600,
+                (at % 3600) / 60,
+                zone.name()
+            ));
+        }
+        if let Some(window) = self.window {
+            let mut part = format!("between {}", window.describe());
+            if self.at.is_none() {
+                part.push_str(&format!(" in {}", zone.name()));
+            }
+            parts.push(part);
+        }
+        match parts.is_empty() {
+            true => None,
+            false => Some(parts.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_schedule, HOUR_MILLIS, MINUTE_MILLIS};
+    use crate::tz::TzData;
+    use crate::utils::{days_from_civil, format_rfc3339};
+
+    fn berlin() -> Zone {
+        Zone::Named(
+            "Europe/Berlin".to_string(),
+            TzData::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap(),
+        )
+    }
+
+    /// epoch milliseconds for a UTC date and time
+    fn utc(year: i64, month: u32, day: u32, hour: u128, minute: u128) -> u128 {
+        days_from_civil(year, month, day) as u128 * DAY_MILLIS
+            + hour * HOUR_MILLIS
+            + minute * MINUTE_MILLIS
+    }
+
+    /// formats epoch milliseconds in Berlin time
+    fn local(zone: &Zone, ms: u128) -> String {
+        format_rfc3339(ms, zone.offset_at((ms / 1000) as i64))
+    }
+
+    /// runs evry every `step` from `start` to `end` with a fake clock,
+    /// returning when it would've run (in Berlin time)
+    fn simulate(schedule: &Schedule, start: u128, end: u128, step: u128) -> Vec<String> {
+        simulate_while(schedule, start, end, step, |_| true)
+    }
+
+    /// like `simulate`, but evry only runs while `is_on` (e.g. when the laptop isn't off)
+    fn simulate_while(
+        schedule: &Schedule,
+        start: u128,
+        end: u128,
+        step: u128,
+        is_on: impl Fn(u128) -> bool,
+    ) -> Vec<String> {
+        let zone = berlin();
+        let mut last_run = None;
+        let mut runs = vec![];
+        let mut now = start;
+        while now < end {
+            let due = match last_run {
+                Some(last_run) => now >= schedule.next_run(last_run, &zone),
+                None => true,
+            } && schedule.can_run_at(now, &zone);
+            if due && is_on(now) {
+                last_run = Some(now);
+                runs.push(local(&zone, now));
+            }
+            now += step;
+        }
+        runs
+    }
+
+    #[test]
+    fn test_unanchored() {
+        let schedule = parse_schedule("2 hours").unwrap();
+        assert_eq!(schedule.next_run(1000, &berlin()), 1000 + 2 * HOUR_MILLIS);
+        assert_eq!(schedule.describe(&berlin()), None);
+        assert!(!schedule.is_calendar());
+    }
+
+    #[test]
+    fn test_next_run() {
+        let zone = berlin();
+        let schedule = parse_schedule("1 day at 02:30 in Europe/Berlin").unwrap();
+        assert_eq!(
+            schedule.describe(&zone).unwrap(),
+            "at 02:30 in Europe/Berlin"
+        );
+        // ran at 12:00 CET, so next due at 02:30 CET the next day
+        let next = schedule.next_run(utc(2024, 1, 15, 11, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-01-16T02:30:00+01:00");
+        // ran at 01:00, still due later that day
+        let next = schedule.next_run(utc(2024, 1, 15, 0, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-01-15T02:30:00+01:00");
+        // ran right at 02:30, so the next one is tomorrow
+        let next = schedule.next_run(utc(2024, 1, 15, 1, 30), &zone);
+        assert_eq!(local(&zone, next), "2024-01-16T02:30:00+01:00");
+
+        let schedule = parse_schedule("2 days at 18:00").unwrap();
+        let next = schedule.next_run(utc(2024, 7, 1, 16, 5), &zone);
+        assert_eq!(local(&zone, next), "2024-07-03T18:00:00+02:00");
+    }
+
+    #[test]
+    fn test_dst_fake_clock() {
+        let schedule = parse_schedule("1 day at 02:30 in Europe/Berlin").unwrap();
+        // checking every 10 minutes over the weekend the clocks go forward,
+        // 02:30 doesn't exist on the 31st, so it runs at 03:30 instead
+        let runs = simulate(
+            &schedule,
+            utc(2024, 3, 29, 12, 0),
+            utc(2024, 4, 2, 0, 0),
+            10 * MINUTE_MILLIS,
+        );
+        assert_eq!(
+            runs,
+            [
+                "2024-03-29T13:00:00+01:00",
+                "2024-03-30T02:30:00+01:00",
+                "2024-03-31T03:30:00+02:00",
+                "2024-04-01T02:30:00+02:00",
+            ]
+        );
+        // over the weekend the clocks go back, 02:30 happens twice on
+        // the 27th, but it only runs the first time
+        let runs = simulate(
+            &schedule,
+            utc(2024, 10, 25, 12, 0),
+            utc(2024, 10, 29, 0, 0),
+            10 * MINUTE_MILLIS,
+        );
+        assert_eq!(
+            runs,
+            [
+                "2024-10-25T14:00:00+02:00",
+                "2024-10-26T02:30:00+02:00",
+                "2024-10-27T02:30:00+02:00",
+                "2024-10-28T02:30:00+01:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_business_days() {
+        let zone = berlin();
+        // 2024-07-05 is a Friday
+        let friday = utc(2024, 7, 5, 8, 0);
+        let schedule = parse_schedule("1 business day").unwrap();
+        assert!(schedule.is_calendar());
+        let next = schedule.next_run(friday, &zone);
+        assert_eq!(local(&zone, next), "2024-07-08T10:00:00+02:00");
+        // from a Saturday, the next business day is still Monday
+        let next = schedule.next_run(friday + DAY_MILLIS, &zone);
+        assert_eq!(local(&zone, next), "2024-07-08T10:00:00+02:00");
+        let schedule = parse_schedule("2 business days, 1 hour").unwrap();
+        let next = schedule.next_run(friday - DAY_MILLIS, &zone);
+        assert_eq!(local(&zone, next), "2024-07-08T11:00:00+02:00");
+
+        let schedule = parse_schedule("1 weekday at 09:00").unwrap();
+        let runs = simulate(
+            &schedule,
+            utc(2024, 7, 4, 12, 0),
+            utc(2024, 7, 10, 12, 0),
+            10 * MINUTE_MILLIS,
+        );
+        assert_eq!(
+            runs,
+            [
+                "2024-07-04T14:00:00+02:00",
+                "2024-07-05T09:00:00+02:00",
+                "2024-07-08T09:00:00+02:00",
+                "2024-07-09T09:00:00+02:00",
+                "2024-07-10T09:00:00+02:00",
+            ]
+        );
+
+        // the laptop is off on Friday, so its overdue over the weekend,
+        // but it waits until Monday instead of running on Saturday
+        let off = utc(2024, 7, 4, 22, 0)..utc(2024, 7, 5, 22, 0);
+        let schedule = parse_schedule("1 business day").unwrap();
+        let runs = simulate_while(
+            &schedule,
+            utc(2024, 7, 4, 12, 0),
+            utc(2024, 7, 9, 12, 0),
+            10 * MINUTE_MILLIS,
+            |now| !off.contains(&now),
+        );
+        assert_eq!(
+            runs,
+            [
+                "2024-07-04T14:00:00+02:00",
+                "2024-07-08T00:00:00+02:00",
+                "2024-07-09T00:00:00+02:00",
+            ]
+        );
+        // with a time of day, it waits until then on Monday
+        let schedule = parse_schedule("1 weekday at 09:00").unwrap();
+        let saturday = utc(2024, 7, 6, 10, 0);
+        assert!(!schedule.can_run_at(saturday, &zone));
+        assert_eq!(
+            local(&zone, schedule.next_allowed(saturday, &zone)),
+            "2024-07-08T09:00:00+02:00"
+        );
+        // the first run waits for a business day as well
+        let schedule = parse_schedule("1 business day").unwrap();
+        assert!(!schedule.can_run_at(saturday, &zone));
+        assert_eq!(
+            local(&zone, schedule.next_allowed(saturday, &zone)),
+            "2024-07-08T00:00:00+02:00"
+        );
+        // unless the days it runs on are listed
+        let schedule = parse_schedule("1 business day on sat").unwrap();
+        assert!(schedule.can_run_at(saturday, &zone));
+    }
+
+    #[test]
+    fn test_days_of_week() {
+        let zone = berlin();
+        let schedule = parse_schedule("2 business days on mon,wed at 09:00").unwrap();
+        assert_eq!(
+            schedule.describe(&zone).unwrap(),
+            "every 2 business days on mon,wed at 09:00 in Europe/Berlin"
+        );
+
+        let schedule = parse_schedule("1 day on mon,wed,fri at 09:00").unwrap();
+        let runs = simulate(
+            &schedule,
+            utc(2024, 6, 30, 12, 0),
+            utc(2024, 7, 8, 12, 0),
+            10 * MINUTE_MILLIS,
+        );
+        // the first check is on a Sunday, so it waits until Monday
+        assert_eq!(
+            runs,
+            [
+                "2024-07-01T00:00:00+02:00",
+                "2024-07-01T09:00:00+02:00",
+                "2024-07-03T09:00:00+02:00",
+                "2024-07-05T09:00:00+02:00",
+                "2024-07-08T09:00:00+02:00",
+            ]
+        );
+
+        // without a time of day, it waits until the start of the next day it can run on
+        let schedule = parse_schedule("12 hours on sat,sun").unwrap();
+        let next = schedule.next_run(utc(2024, 7, 5, 18, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-06T08:00:00+02:00");
+        let next = schedule.next_run(utc(2024, 7, 7, 16, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-13T00:00:00+02:00");
+        // overdue, but it can't run on a Monday
+        let monday = utc(2024, 7, 8, 10, 0);
+        assert!(!schedule.can_run_at(monday, &zone));
+        assert_eq!(
+            local(&zone, schedule.next_allowed(monday, &zone)),
+            "2024-07-13T00:00:00+02:00"
+        );
+        assert!(schedule.can_run_at(next, &zone));
+
+        assert_eq!(
+            Weekdays::from_names(["Monday", "wed", "SUN"])
+                .unwrap()
+                .describe(),
+            "mon,wed,sun"
+        );
+        assert!(Weekdays::from_names(["mon", "funday"]).is_err());
+        // only the full name or the abbreviation
+        assert!(Weekdays::from_names(["monkey"]).is_err());
+        assert!(Weekdays::from_names(["mo"]).is_err());
+        assert!(Weekdays::from_names(["wednes"]).is_err());
+        assert!(Weekdays::from_names([]).is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let zone = berlin();
+        let mut schedule = parse_schedule("1 day").unwrap();
+        schedule.window = Some("01:00-05:00".parse().unwrap());
+        assert!(schedule.is_calendar());
+        assert_eq!(
+            schedule.describe(&zone).unwrap(),
+            "between 01:00-05:00 in Europe/Berlin"
+        );
+        // due at noon, but it waits until the window opens
+        let next = schedule.next_run(utc(2024, 7, 1, 10, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-03T01:00:00+02:00");
+        let next = schedule.next_run(utc(2024, 7, 1, 1, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-02T03:00:00+02:00");
+        // overdue, but the window has closed
+        let now = utc(2024, 7, 2, 4, 0);
+        assert!(!schedule.can_run_at(now, &zone));
+        assert_eq!(
+            local(&zone, schedule.next_allowed(now, &zone)),
+            "2024-07-03T01:00:00+02:00"
+        );
+        let runs = simulate(
+            &schedule,
+            utc(2024, 7, 1, 10, 0),
+            utc(2024, 7, 4, 10, 0),
+            10 * MINUTE_MILLIS,
+        );
+        assert_eq!(
+            runs,
+            [
+                "2024-07-02T01:00:00+02:00",
+                "2024-07-03T01:00:00+02:00",
+                "2024-07-04T01:00:00+02:00",
+            ]
+        );
+
+        // wrapping past midnight, only on weekends
+        let mut schedule = parse_schedule("1 hour on sat,sun").unwrap();
+        schedule.window = Some("22:00-02:00".parse().unwrap());
+        // 2024-07-05 is a Friday
+        let next = schedule.next_run(utc(2024, 7, 5, 10, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-06T00:00:00+02:00");
+        let next = schedule.next_run(utc(2024, 7, 6, 0, 0), &zone);
+        assert_eq!(local(&zone, next), "2024-07-06T22:00:00+02:00");
+        let next = schedule.next_run(utc(2024, 7, 6, 21, 30), &zone);
+        assert_eq!(local(&zone, next), "2024-07-07T00:30:00+02:00");
+        let next = schedule.next_run(utc(2024, 7, 7, 23, 30), &zone);
+        assert_eq!(local(&zone, next), "2024-07-13T00:00:00+02:00");
+
+        let window: Window = "22:00-02:30".parse().unwrap();
+        assert!(window.contains(23 * 3600) && window.contains(3600));
+        assert!(!window.contains(3 * 3600));
+        assert_eq!(window.describe(), "22:00-02:30");
+        assert!("01:00".parse::<Window>().is_err());
+        assert!("01:00-01:00".parse::<Window>().is_err());
+        assert!("01:00-25:00".parse::<Window>().is_err());
+    }
+}
@@ -0,0 +1,482 @@
+//! Describes the state of every job in a job file, used by `evry status`
+
+use anyhow::{Context, Error, Result};
+use serde::Serialize;
+
+use crate::{
+    file::{self, LocalDir, Tag},
+    jobs::Job,
+    schedule::Schedule,
+    tz::Zone,
+    utils::DurationFormat,
+};
+
+/// a job is overdue once its been due for this many
+/// intervals, e.g. a daily job which hasn't run in 2 days
+pub const OVERDUE_FACTOR: u128 = 2;
+
+/// by default, `evry audit` considers a tag stale once its gone
+/// 3 intervals without running, e.g. a daily job which hasn't run in 3 days
+pub const DEFAULT_GRACE: f64 = 3.0;
+
+/// Whether or not a job would run if evry was called now
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// the job has run within its interval
+    Waiting,
+    /// the interval has elapsed (or the job has never run)
+    Due,
+    /// the job has been due for at least another full interval
+    Overdue,
+}
+
+impl State {
+    /// description used in the status table
+    pub fn describe(&self) -> &'static str {
+        match self {
+            State::Waiting => "waiting",
+            State::Due => "due",
+            State::Overdue => "overdue",
+        }
+    }
+}
+
+/// The state of a single job
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    pub tag: String,
+    pub interval_ms: u128,
+    /// when this tag last ran (epoch milliseconds), `None` if it never has
+    pub last_run_ms: Option<u128>,
+    pub state: State,
+    /// when this job will next be able to run (epoch milliseconds), which
+    /// is when its window next opens if its due outside of it
+    pub next_run_ms: u128,
+    /// milliseconds until `next_run_ms`, 0 if its already due
+    pub till_next_ms: u128,
+    /// exit code of the last run, if it was saved with `evry outcome`
+    pub exit_code: Option<i32>,
+}
+
+impl JobStatus {
+    /// computes the status of a job from its tag file
+    pub fn new(job: &Job, local_dir: &LocalDir, now: u128) -> Result<Self, Error> {
+        let tag = Tag::new(job.tag.clone(), local_dir)?;
+        let saved = match tag.file_exists() {
+            true => Some(tag.read_state()?),
+            false => None,
+        };
+        let last_run_ms = saved.as_ref().map(|state| state.last_run);
+        let exit_code = saved
+            .as_ref()
+            .and_then(|state| state.get::<i32>(file::EXIT_CODE_KEY));
+        let schedule = &job.schedule;
+        let zone = Zone::load(schedule.timezone.as_deref())
+            .context(format!("Couldn't load the timezone for job '{}'", job.tag))?;
+        let (state, next_run_ms) = match last_run_ms {
+            None => (State::Due, schedule.next_allowed(now, &zone)),
+            Some(last_run) => {
+                // when its next due, which depends on the time of day/days of the week it runs on
+                let next_run = schedule.next_run(last_run, &zone);
+                let state = if now <= next_run {
+                    State::Waiting
+                } else if now - next_run > schedule.every * (OVERDUE_FACTOR - 1) {
+                    State::Overdue
+                } else {
+                    State::Due
+                };
+                match next_run <= now && !schedule.can_run_at(now, &zone) {
+                    true => (state, schedule.next_allowed(now, &zone)),
+                    false => (state, next_run),
+                }
+            }
+        };
+        Ok(Self {
+            tag: job.tag.clone(),
+            interval_ms: schedule.every,
+            last_run_ms,
+            state,
+            next_run_ms,
+            till_next_ms: next_run_ms.saturating_sub(now),
+            exit_code,
+        })
+    }
+}
+
+/// computes the status of every job, sorted by when they're next due.
+///
+/// Jobs whose tag file can't be read are skipped, and their errors are returned
+pub fn job_statuses(jobs: &[Job], local_dir: &LocalDir, now: u128) -> (Vec<JobStatus>, Vec<Error>) {
+    let mut statuses = vec![];
+    let mut errors = vec![];
+    for job in jobs {
+        match JobStatus::new(job, local_dir, now) {
+            Ok(status) => statuses.push(status),
+            Err(e) => errors.push(e),
+        }
+    }
+    statuses.sort_by(|a, b| {
+        a.next_run_ms
+            .cmp(&b.next_run_ms)
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
+    (statuses, errors)
+}
+
+/// A tag which hasn't run in (much) longer than its interval
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StaleTag {
+    pub tag: String,
+    pub interval_ms: u128,
+    pub last_run_ms: u128,
+    /// milliseconds since the tag last ran
+    pub age_ms: u128,
+}
+
+/// finds every tag which hasn't run in `grace` times its interval
+///
+/// Jobs in the job file use their interval from the job file, any other tags
+/// use the interval they were last run with (tags which were last run by
+/// older versions of evry don't have one, so they're skipped).
+/// Jobs which have never run aren't stale, they just haven't been run yet.
+/// Tags which can't be read (or have an invalid name) are skipped, and their errors are returned
+pub fn audit(
+    jobs: &[Job],
+    local_dir: &LocalDir,
+    now: u128,
+    grace: f64,
+) -> Result<(Vec<StaleTag>, Vec<Error>), Error> {
+    let mut stale = vec![];
+    let mut errors = vec![];
+    // stale once its been due for `grace - 1` more intervals, so a time of day or the
+    // days of the week it runs on push back when its considered stale
+    let mut check = |name: &str, schedule: &Schedule, zone: &Zone, state: &file::TagState| {
+        let next_run = schedule.next_run(state.last_run, zone);
+        let overdue_ms = now as f64 - next_run as f64;
+        if overdue_ms > schedule.every as f64 * (grace - 1.0) {
+            stale.push(StaleTag {
+                tag: name.to_string(),
+                interval_ms: schedule.every,
+                last_run_ms: state.last_run,
+                age_ms: now.saturating_sub(state.last_run),
+            });
+        }
+    };
+    for job in jobs {
+        let checked = Tag::new(job.tag.clone(), local_dir).and_then(|tag| {
+            if tag.file_exists() {
+                let zone = Zone::load(job.schedule.timezone.as_deref())
+                    .context(format!("Couldn't load the timezone for job '{}'", job.tag))?;
+                check(&job.tag, &job.schedule, &zone, &tag.read_state()?);
+            }
+            Ok(())
+        });
+        if let Err(e) = checked {
+            errors.push(e);
+        }
+    }
+    for name in local_dir.list_tags(None)? {
+        if jobs.iter().any(|job| job.tag == name) {
+            continue;
+        }
+        match Tag::new(name.clone(), local_dir).and_then(|tag| tag.read_state()) {
+            Ok(state) => {
+                if let Some(every) = state.get::<u128>(file::INTERVAL_KEY) {
+                    let schedule = Schedule {
+                        every,
+                        ..Default::default()
+                    };
+                    check(&name, &schedule, &Zone::Local, &state);
+                }
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    stale.sort_by(|a, b| a.tag.cmp(&b.tag));
+    Ok((stale, errors))
+}
+
+/// formats the statuses as a table, with a header row
+pub fn format_table(statuses: &[JobStatus], now: u128, format: &DurationFormat) -> String {
+    let mut rows: Vec<[String; 6]> = vec![[
+        "TAG".to_string(),
+        "INTERVAL".to_string(),
+        "LAST RUN".to_string(),
+        "STATE".to_string(),
+        "NEXT RUN".to_string(),
+        "OUTCOME".to_string(),
+    ]];
+    for status in statuses {
+        rows.push([
+            status.tag.clone(),
+            format.format(status.interval_ms),
+            match status.last_run_ms {
+                Some(last_run) => format!("{} ago", format.format(now.saturating_sub(last_run))),
+                None => "never".to_string(),
+            },
+            status.state.describe().to_string(),
+            match status.till_next_ms {
+                0 => "now".to_string(),
+                till_next => format!("in {}", format.format(till_next)),
+            },
+            match status.exit_code {
+                Some(0) => "ok".to_string(),
+                Some(code) => format!("exit {}", code),
+                None => "-".to_string(),
+            },
+        ]);
+    }
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn job(tag: &str, every_ms: u128) -> Job {
+        Job {
+            tag: tag.to_string(),
+            every: format!("{}ms", every_ms),
+            schedule: Schedule {
+                every: every_ms,
+                ..Default::default()
+            },
+            command: None,
+        }
+    }
+
+    #[test]
+    fn test_job_statuses() {
+        let data_dir =
+            std::env::temp_dir().join(format!("evry-test-status-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        let now = 100_000;
+        let mut state = file::TagState {
+            last_run: now - 5_000,
+            ..Default::default()
+        };
+        state.set(file::EXIT_CODE_KEY, 1);
+        Tag::new("waiting".to_string(), &local_dir)
+            .unwrap()
+            .write_state(&state)
+            .unwrap();
+        // written by older versions of evry, without an interval
+        for (name, last_run) in [("due", now - 15_000), ("overdue", now - 50_000)] {
+            Tag::new(name.to_string(), &local_dir)
+                .unwrap()
+                .write_state(&file::TagState {
+                    last_run,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let jobs = vec![
+            job("waiting", 10_000),
+            job("due", 10_000),
+            job("overdue", 10_000),
+            job("new", 10_000),
+        ];
+        let (statuses, errors) = job_statuses(&jobs, &local_dir, now);
+        assert!(errors.is_empty());
+
+        let summary: Vec<(&str, State, Option<i32>, u128)> = statuses
+            .iter()
+            .map(|s| (s.tag.as_str(), s.state, s.exit_code, s.till_next_ms))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("overdue", State::Overdue, None, 0),
+                ("due", State::Due, None, 0),
+                ("new", State::Due, None, 0),
+                ("waiting", State::Waiting, Some(1), 5_000),
+            ]
+        );
+
+        // 'due' and 'overdue' don't have a saved interval, so they're only
+        // stale if they're in the job file
+        Tag::new("saved".to_string(), &local_dir)
+            .unwrap()
+            .write(now - 35_000, 10_000, &crate::utils::Clocks::default())
+            .unwrap();
+        let stale: Vec<String> = audit(&jobs[..2], &local_dir, now, 1.0)
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|s| s.tag)
+            .collect();
+        assert_eq!(stale, vec!["due", "saved"]);
+        let stale: Vec<String> = audit(&jobs, &local_dir, now, 3.0)
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|s| s.tag)
+            .collect();
+        assert_eq!(stale, vec!["overdue", "saved"]);
+
+        // a corrupt tag file is reported, without stopping the other tags from being checked
+        std::fs::write(data_dir.join("due"), "not a timestamp").unwrap();
+        let (stale, errors) = audit(&jobs, &local_dir, now, 3.0).unwrap();
+        assert_eq!(
+            stale.into_iter().map(|s| s.tag).collect::<Vec<String>>(),
+            vec!["overdue", "saved"]
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{:#}", errors[0]).contains("due"));
+        let (statuses, errors) = job_statuses(&jobs, &local_dir, now);
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(errors.len(), 1);
+
+        // so is a job with an invalid tag name
+        let mut jobs = jobs;
+        jobs.push(job("backup//photos", 10_000));
+        let (stale, errors) = audit(&jobs, &local_dir, now, 3.0).unwrap();
+        assert_eq!(stale.len(), 2);
+        assert_eq!(errors.len(), 2);
+        assert!(format!("{:#}", errors[1]).contains("'backup//photos' has an empty namespace"));
+        let (statuses, errors) = job_statuses(&jobs, &local_dir, now);
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(errors.len(), 2);
+        std::fs::remove_dir_all(PathBuf::from(&data_dir)).unwrap();
+    }
+
+    #[test]
+    fn test_calendar_status() {
+        // the tz database isn't installed everywhere
+        if Zone::load(Some("UTC")).is_err() {
+            return;
+        }
+        let data_dir =
+            std::env::temp_dir().join(format!("evry-test-calendar-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        // 2023-10-18 12:00 UTC, both tags last ran at 2023-10-17 20:00 UTC
+        let now = 1697630400000;
+        for name in ["anchored", "plain"] {
+            Tag::new(name.to_string(), &local_dir)
+                .unwrap()
+                .write_state(&file::TagState {
+                    last_run: 1697572800000,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let mut anchored = job("anchored", 0);
+        anchored.every = "1 day at 02:30 in UTC".to_string();
+        anchored.schedule = crate::parser::parse_schedule(&anchored.every).unwrap();
+        let jobs = vec![anchored, job("plain", 86400000)];
+
+        // the anchored job was due at 02:30, the plain one is due at 20:00
+        let (statuses, errors) = job_statuses(&jobs, &local_dir, now);
+        assert!(errors.is_empty());
+        let summary: Vec<(&str, State, u128)> = statuses
+            .iter()
+            .map(|s| (s.tag.as_str(), s.state, s.next_run_ms))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("anchored", State::Due, 1697596200000),
+                ("plain", State::Waiting, 1697659200000),
+            ]
+        );
+        let (stale, errors) = audit(&jobs, &local_dir, now, 1.0).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(
+            stale.into_iter().map(|s| s.tag).collect::<Vec<String>>(),
+            vec!["anchored"]
+        );
+
+        let mut unknown = job("plain", 86400000);
+        unknown.schedule.timezone = Some("Not/A_Zone".to_string());
+        let (statuses, errors) = job_statuses(&[unknown], &local_dir, now);
+        assert!(statuses.is_empty());
+        assert!(format!("{:#}", errors[0]).contains("job 'plain'"));
+        std::fs::remove_dir_all(PathBuf::from(&data_dir)).unwrap();
+    }
+
+    #[test]
+    fn test_weekday_status() {
+        if Zone::load(Some("UTC")).is_err() {
+            return;
+        }
+        let data_dir =
+            std::env::temp_dir().join(format!("evry-test-weekdays-{}", std::process::id()));
+        let local_dir = LocalDir {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        };
+        // Sunday 2023-10-22 12:00 UTC
+        let now = 1697976000000;
+        let mut jobs = vec![];
+        // (tag, every, last run)
+        for (name, every, last_run) in [
+            // Friday 2023-10-20 20:00
+            ("standup", "1 business day", 1697832000000),
+            ("plain", "1 day", 1697832000000),
+            // Monday 2023-10-16 10:00
+            ("gym", "1 day on mon,wed", 1697450400000),
+        ] {
+            Tag::new(name.to_string(), &local_dir)
+                .unwrap()
+                .write_state(&file::TagState {
+                    last_run,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut job = job(name, 0);
+            job.every = every.to_string();
+            job.schedule = crate::parser::parse_schedule(every).unwrap();
+            // so this doesn't depend on the local timezone
+            job.schedule.timezone = Some("UTC".to_string());
+            jobs.push(job);
+        }
+        let (statuses, errors) = job_statuses(&jobs, &local_dir, now);
+        assert!(errors.is_empty());
+        let summary: Vec<(&str, State, u128)> = statuses
+            .iter()
+            .map(|s| (s.tag.as_str(), s.state, s.next_run_ms))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                // Saturday 20:00
+                ("plain", State::Due, 1697918400000),
+                // was due on Wednesday, but it can't run until Monday
+                ("gym", State::Overdue, 1698019200000),
+                // skips the weekend, Monday 20:00
+                ("standup", State::Waiting, 1698091200000),
+            ]
+        );
+        let (stale, _) = audit(&jobs, &local_dir, now, 1.0).unwrap();
+        assert_eq!(
+            stale.into_iter().map(|s| s.tag).collect::<Vec<String>>(),
+            vec!["gym", "plain"]
+        );
+        std::fs::remove_dir_all(PathBuf::from(&data_dir)).unwrap();
+    }
+}
@@ -0,0 +1,500 @@
+//! Timezones, used by schedules anchored to a time of day like `1d at 02:30 in Europe/Berlin`
+//!
+//! Named timezones are read from the system tz database (the TZif files
+//! in /usr/share/zoneinfo, or `$TZDIR`). Schedules which don't name a
+//! timezone use the local timezone, through libc
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use std::{env, fs, path::PathBuf};
+
+use crate::utils::{
+    civil_from_days, days_from_civil, local_offset_seconds, weekday, SECONDS_PER_DAY,
+};
+
+/// where the tz database is installed, if `TZDIR` isn't set
+#[doc(hidden)]
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// A day of the year daylight saving time starts or ends on, from a POSIX TZ string
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateRule {
+    /// `Jn`, day 1-365, not counting February 29th
+    Julian(u32),
+    /// `n`, day 0-365, counting February 29th
+    ZeroJulian(u32),
+    /// `Mm.w.d`, weekday d (0 is Sunday) of week w (5 is the last week) of month m
+    MonthWeekDay(u32, u32, u32),
+}
+
+impl DateRule {
+    /// the day this falls on in some year, as days since the epoch
+    fn day(&self, year: i64) -> i64 {
+        let jan_first = days_from_civil(year, 1, 1);
+        match *self {
+            DateRule::Julian(n) => {
+                let leap = days_from_civil(year, 3, 1) - days_from_civil(year, 2, 1) == 29;
+                jan_first + n as i64 - 1 + i64::from(leap && n >= 60)
+            }
+            DateRule::ZeroJulian(n) => jan_first + n as i64,
+            DateRule::MonthWeekDay(month, week, day_of_week) => {
+                let first = days_from_civil(year, month, 1);
+                let next_month = match month {
+                    12 => days_from_civil(year + 1, 1, 1),
+                    _ => days_from_civil(year, month + 1, 1),
+                };
+                let first_weekday = weekday(first) as i64;
+                let mut day = first + (day_of_week as i64 - first_weekday).rem_euclid(7);
+                day += (week as i64 - 1) * 7;
+                // week 5 means the last one, which might be the 4th
+                while day >= next_month {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+/// When daylight saving time starts or ends: a day, and the
+/// local time (seconds after midnight, can be negative or past 24h)
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transition {
+    date: DateRule,
+    time: i64,
+}
+
+impl Transition {
+    /// when this happens in some year (UTC seconds), given the offset before it
+    fn utc(&self, year: i64, offset: i64) -> i64 {
+        self.date.day(year) * SECONDS_PER_DAY + self.time - offset
+    }
+}
+
+/// A POSIX TZ string like `CET-1CEST,M3.5.0,M10.5.0/3`, which describes
+/// the offsets for times after the last transition in a TZif file
+#[derive(Debug, Clone, PartialEq)]
+struct PosixTz {
+    /// offset from UTC (seconds east) in standard time
+    std_offset: i64,
+    /// offset in daylight saving time, and when it starts and ends
+    dst: Option<(i64, Transition, Transition)>,
+}
+
+impl PosixTz {
+    fn parse(tz: &str) -> Result<Self, Error> {
+        let mut rest = tz;
+        parse_name(&mut rest)?;
+        let std_offset = -parse_offset(&mut rest)?;
+        if rest.is_empty() {
+            return Ok(Self {
+                std_offset,
+                dst: None,
+            });
+        }
+        parse_name(&mut rest)?;
+        let dst_offset = match rest.starts_with(|c: char| c != ',') {
+            true => -parse_offset(&mut rest)?,
+            false => std_offset + 3600,
+        };
+        // the default rule when one isn't given is the US rule
+        let rules = match rest {
+            "" => ",M3.2.0,M11.1.0",
+            _ => rest,
+        };
+        let mut rules = rules.strip_prefix(',').unwrap_or(rules).split(',');
+        let (Some(start), Some(end), None) = (rules.next(), rules.next(), rules.next()) else {
+            bail!("Expected a start and end rule in TZ string '{}'", tz);
+        };
+        Ok(Self {
+            std_offset,
+            dst: Some((dst_offset, parse_transition(start)?, parse_transition(end)?)),
+        })
+    }
+
+    fn offset_at(&self, utc: i64) -> i64 {
+        let Some((dst_offset, start, end)) = &self.dst else {
+            return self.std_offset;
+        };
+        let (year, _, _) = civil_from_days((utc + self.std_offset).div_euclid(SECONDS_PER_DAY));
+        // the start is in standard time, and the end is in daylight saving time
+        let start = start.utc(year, self.std_offset);
+        let end = end.utc(year, *dst_offset);
+        let in_dst = match start < end {
+            true => start <= utc && utc < end,
+            // southern hemisphere, daylight saving time spans the new year
+            false => !(end <= utc && utc < start),
+        };
+        match in_dst {
+            true => *dst_offset,
+            false => self.std_offset,
+        }
+    }
+}
+
+/// consumes a timezone abbreviation, like `CET` or `<+0330>`
+fn parse_name(rest: &mut &str) -> Result<(), Error> {
+    let end = match rest.strip_prefix('<') {
+        Some(quoted) => quoted.find('>').map(|i| i + 2),
+        None => Some(
+            rest.find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len()),
+        ),
+    };
+    match end {
+        Some(end) if end > 0 => {
+            *rest = &rest[end..];
+            Ok(())
+        }
+        _ => bail!("Expected a timezone abbreviation in '{}'", rest),
+    }
+}
+
+/// parses `[+-]hh[:mm[:ss]]` into seconds
+fn parse_hms(hms: &str) -> Result<i64, Error> {
+    let (sign, hms) = match hms.strip_prefix('-') {
+        Some(hms) => (-1, hms),
+        None => (1, hms.strip_prefix('+').unwrap_or(hms)),
+    };
+    let mut seconds = 0;
+    for (i, part) in hms.split(':').enumerate() {
+        let value = match part.parse::<i64>() {
+            Ok(value) if i < 3 && part.len() <= 3 => value,
+            _ => bail!("Could not parse '{}' as a time", hms),
+        };
+        seconds += value * [3600, 60, 1][i];
+    }
+    Ok(sign * seconds)
+}
+
+/// consumes an offset like `-1` or `+05:30`, in seconds west of UTC
+fn parse_offset(rest: &mut &str) -> Result<i64, Error> {
+    let end = rest
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == ':' || (*i == 0 && "+-".contains(*c))))
+        .map_or(rest.len(), |(i, _)| i);
+    let offset = parse_hms(&rest[..end])?;
+    *rest = &rest[end..];
+    Ok(offset)
+}
+
+/// parses a rule like `M3.5.0/3`, the time defaults to 02:00
+fn parse_transition(rule: &str) -> Result<Transition, Error> {
+    let (date, time) = match rule.split_once('/') {
+        Some((date, time)) => (date, parse_hms(time)?),
+        None => (rule, 7200),
+    };
+    let number = |n: &str| {
+        n.parse::<u32>()
+            .map_err(|_| anyhow!("Could not parse TZ rule '{}'", rule))
+    };
+    let date = if let Some(day) = date.strip_prefix('J') {
+        DateRule::Julian(number(day)?)
+    } else if let Some(mwd) = date.strip_prefix('M') {
+        let parts = mwd.split('.').map(number).collect::<Result<Vec<u32>>>()?;
+        match parts[..] {
+            [month @ 1..=12, week @ 1..=5, weekday @ 0..=6] => {
+                DateRule::MonthWeekDay(month, week, weekday)
+            }
+            _ => bail!("Could not parse TZ rule '{}'", rule),
+        }
+    } else {
+        DateRule::ZeroJulian(number(date)?)
+    };
+    Ok(Transition { date, time })
+}
+
+/// The offsets from a TZif file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TzData {
+    /// the offset before the first transition
+    initial: i64,
+    /// when the offset changes (UTC seconds), and what it changes to
+    transitions: Vec<(i64, i64)>,
+    /// the rule for times after the last transition
+    rule: Option<PosixTz>,
+}
+
+impl TzData {
+    /// parses a TZif file, see RFC 8536
+    pub fn parse_tzif(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.get(..4) != Some(b"TZif") {
+            bail!("Not a TZif file");
+        }
+        let version = *bytes.get(4).context("TZif file is truncated")?;
+        let read = |pos: usize, size: usize| -> Result<i64, Error> {
+            let field = bytes
+                .get(pos..pos + size)
+                .context("TZif file is truncated")?;
+            let mut value: i64 = match field[0] & 0x80 {
+                0 => 0,
+                _ => -1,
+            };
+            for byte in field {
+                value = (value << 8) | *byte as i64;
+            }
+            Ok(value)
+        };
+        // isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt
+        let counts = |header: usize| -> Result<Vec<usize>, Error> {
+            (0..6)
+                .map(|i| Ok(read(header + 20 + i * 4, 4)? as u32 as usize))
+                .collect()
+        };
+        let data_len = |counts: &[usize], time_size: usize| {
+            counts[3] * (time_size + 1)
+                + counts[4] * 6
+                + counts[5]
+                + counts[2] * (time_size + 4)
+                + counts[1]
+                + counts[0]
+        };
+        let mut header = 0;
+        let mut time_size = 4;
+        if version >= b'2' {
+            // skip the version 1 data, the second header has 64-bit times
+            header = 44 + data_len(&counts(0)?, 4);
+            time_size = 8;
+        }
+        let counts = counts(header)?;
+        let (timecnt, typecnt) = (counts[3], counts[4]);
+        if typecnt == 0 {
+            bail!("TZif file has no local time types");
+        }
+        let times = header + 44;
+        let indices = times + timecnt * time_size;
+        let types = indices + timecnt;
+        let offset = |index: usize| -> Result<i64, Error> {
+            if index >= typecnt {
+                bail!("TZif file has an invalid local time type");
+            }
+            read(types + index * 6, 4)
+        };
+        let mut transitions = Vec::with_capacity(timecnt);
+        for i in 0..timecnt {
+            let index = read(indices + i, 1)? as u8 as usize;
+            transitions.push((read(times + i * time_size, time_size)?, offset(index)?));
+        }
+        let mut rule = None;
+        if version >= b'2' {
+            let footer = &bytes[(header + 44 + data_len(&counts, 8)).min(bytes.len())..];
+            let footer = String::from_utf8_lossy(footer);
+            let tz = footer.trim_matches('\n');
+            if !tz.is_empty() {
+                rule = Some(PosixTz::parse(tz).context(format!("Invalid TZ string '{}'", tz))?);
+            }
+        }
+        Ok(Self {
+            initial: offset(0)?,
+            transitions,
+            rule,
+        })
+    }
+
+    /// parses a POSIX TZ string, like `CET-1CEST,M3.5.0,M10.5.0/3`, so tests
+    /// don't depend on the tz database being installed
+    #[cfg(test)]
+    pub fn parse_posix(tz: &str) -> Result<Self, Error> {
+        let rule = PosixTz::parse(tz)?;
+        Ok(Self {
+            initial: rule.std_offset,
+            transitions: vec![],
+            rule: Some(rule),
+        })
+    }
+
+    fn offset_at(&self, utc: i64) -> i64 {
+        let after = self.transitions.partition_point(|(at, _)| *at <= utc);
+        match &self.rule {
+            Some(rule) if after == self.transitions.len() => rule.offset_at(utc),
+            _ if after == 0 => self.initial,
+            _ => self.transitions[after - 1].1,
+        }
+    }
+}
+
+/// A timezone, to convert between UTC and local times
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zone {
+    /// the system timezone (`TZ`, or /etc/localtime)
+    Local,
+    /// a timezone from the tz database, like Europe/Berlin
+    Named(String, TzData),
+}
+
+impl Zone {
+    /// loads a timezone from the tz database, or the local timezone if `None`
+    pub fn load(name: Option<&str>) -> Result<Self, Error> {
+        let Some(name) = name else {
+            return Ok(Zone::Local);
+        };
+        if name.is_empty() || name.starts_with('/') || name.split('/').any(|part| part == "..") {
+            bail!("Invalid timezone '{}'", name);
+        }
+        let dir = env::var_os("TZDIR").map_or(PathBuf::from(ZONEINFO_DIR), PathBuf::from);
+        let path = dir.join(name);
+        // not attached as a source, since this isn't a problem with the data directory
+        let bytes = fs::read(&path).map_err(|e| {
+            anyhow!(
+                "Unknown timezone '{}', couldn't read {}: {}",
+                name,
+                path.display(),
+                e
+            )
+        })?;
+        let data = TzData::parse_tzif(&bytes)
+            .context(format!("Could not parse timezone file {}", path.display()))?;
+        Ok(Zone::Named(name.to_string(), data))
+    }
+
+    /// the name of the timezone, for debug messages
+    pub fn name(&self) -> &str {
+        match self {
+            Zone::Local => "local time",
+            Zone::Named(name, _) => name,
+        }
+    }
+
+    /// offset from UTC (seconds east) at some time (UTC seconds)
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        match self {
+            Zone::Local => local_offset_seconds(utc),
+            Zone::Named(_, data) => data.offset_at(utc),
+        }
+    }
+
+    /// converts UTC seconds to local seconds (the local time as if it was in UTC)
+    pub fn to_local(&self, utc: i64) -> i64 {
+        utc + self.offset_at(utc)
+    }
+
+    /// converts local seconds back to UTC seconds
+    ///
+    /// When the clocks go back, a local time happens twice, the first one is used.
+    /// When the clocks go forward, a local time is skipped, its moved forward by
+    /// the length of the gap, so 02:30 becomes 03:30 (with a one hour gap)
+    pub fn to_utc(&self, local: i64) -> i64 {
+        // the offsets on either side of a transition near this time
+        let before = self.offset_at(local - SECONDS_PER_DAY);
+        let after = self.offset_at(local + SECONDS_PER_DAY);
+        [local - before, local - after]
+            .into_iter()
+            .filter(|utc| self.to_local(*utc) == local)
+            .min()
+            .unwrap_or(local - before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Europe/Berlin, which switches to daylight saving time on the
+    /// last Sunday in March and back on the last Sunday in October
+    fn berlin() -> Zone {
+        Zone::Named(
+            "Europe/Berlin".to_string(),
+            TzData::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap(),
+        )
+    }
+
+    /// UTC seconds for a date and time
+    fn utc(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn test_posix_offsets() {
+        let berlin = berlin();
+        assert_eq!(berlin.offset_at(utc(2024, 1, 15, 12, 0)), 3600);
+        assert_eq!(berlin.offset_at(utc(2024, 7, 15, 12, 0)), 7200);
+        // 2024-03-31 01:00 UTC the clocks go forward
+        assert_eq!(berlin.offset_at(utc(2024, 3, 31, 0, 59)), 3600);
+        assert_eq!(berlin.offset_at(utc(2024, 3, 31, 1, 0)), 7200);
+        // 2024-10-27 01:00 UTC they go back
+        assert_eq!(berlin.offset_at(utc(2024, 10, 27, 0, 59)), 7200);
+        assert_eq!(berlin.offset_at(utc(2024, 10, 27, 1, 0)), 3600);
+
+        // southern hemisphere, and an offset with minutes
+        let adelaide = TzData::parse_posix("ACST-9:30ACDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(adelaide.offset_at(utc(2024, 1, 15, 0, 0)), 10 * 3600 + 1800);
+        assert_eq!(adelaide.offset_at(utc(2024, 7, 15, 0, 0)), 9 * 3600 + 1800);
+
+        let fixed = TzData::parse_posix("<+0530>-5:30").unwrap();
+        assert_eq!(fixed.offset_at(0), 5 * 3600 + 1800);
+        // no rules defaults to the US rules
+        let new_york = TzData::parse_posix("EST5EDT").unwrap();
+        assert_eq!(new_york.offset_at(utc(2024, 7, 4, 12, 0)), -4 * 3600);
+
+        assert!(TzData::parse_posix("").is_err());
+        assert!(TzData::parse_posix("CET-1CEST,M13.5.0,M10.5.0").is_err());
+    }
+
+    #[test]
+    fn test_dst_transitions() {
+        let berlin = berlin();
+        // ordinary times convert both ways
+        let noon = utc(2024, 7, 15, 10, 0);
+        assert_eq!(berlin.to_utc(berlin.to_local(noon)), noon);
+        // 02:30 doesn't exist on 2024-03-31, so its moved forward to 03:30 CEST
+        assert_eq!(
+            berlin.to_utc(utc(2024, 3, 31, 2, 30)),
+            utc(2024, 3, 31, 1, 30)
+        );
+        // 02:30 happens twice on 2024-10-27, the first (CEST) one is used
+        assert_eq!(
+            berlin.to_utc(utc(2024, 10, 27, 2, 30)),
+            utc(2024, 10, 27, 0, 30)
+        );
+    }
+
+    /// builds a version 2 TZif file with some transitions and a footer
+    fn tzif(transitions: &[(i64, u8)], types: &[i32], footer: &str) -> Vec<u8> {
+        let header = |time_size: usize| {
+            let mut header = b"TZif2".to_vec();
+            header.extend([0; 15]);
+            let counts = [0, 0, 0, transitions.len(), types.len(), 4];
+            for count in counts {
+                header.extend((count as u32).to_be_bytes());
+            }
+            let mut data = vec![];
+            for (time, _) in transitions {
+                data.extend(&time.to_be_bytes()[8 - time_size..]);
+            }
+            data.extend(transitions.iter().map(|(_, index)| index));
+            for offset in types {
+                data.extend(offset.to_be_bytes());
+                data.extend([0, 0]);
+            }
+            data.extend(b"UTC\0");
+            header.extend(data);
+            header
+        };
+        let mut bytes = header(4);
+        bytes.extend(header(8));
+        bytes.extend(format!("\n{}\n", footer).bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_parse_tzif() {
+        let bytes = tzif(&[(1000, 1), (2000, 0)], &[3600, 7200], "CET-1");
+        let data = TzData::parse_tzif(&bytes).unwrap();
+        assert_eq!(data.offset_at(0), 3600);
+        assert_eq!(data.offset_at(1000), 7200);
+        assert_eq!(data.offset_at(1999), 7200);
+        // after the last transition, the footer is used
+        assert_eq!(data.offset_at(utc(2024, 7, 1, 0, 0)), 3600);
+        assert!(TzData::parse_tzif(b"TZif2").is_err());
+        assert!(TzData::parse_tzif(b"TZif").is_err());
+        assert!(TzData::parse_tzif(b"not a tzif file").is_err());
+
+        // the system tz database, if its installed
+        if let Ok(berlin) = Zone::load(Some("Europe/Berlin")) {
+            assert_eq!(berlin.offset_at(utc(2024, 3, 31, 1, 0)), 7200);
+            assert_eq!(berlin.offset_at(utc(1990, 1, 1, 0, 0)), 3600);
+            assert_eq!(berlin.offset_at(utc(2080, 7, 1, 0, 0)), 7200);
+        }
+        assert!(Zone::load(Some("../etc/passwd")).is_err());
+        assert!(Zone::load(Some("Not/A_Zone")).is_err());
+    }
+}
@@ -1,21 +1,204 @@
 //! helper functions to deal with/describe time
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
 use std::time::SystemTime;
 
+use crate::parser::{
+    DAY_MILLIS, HOUR_MILLIS, MINUTE_MILLIS, MONTH_MILLIS, SECOND_MILLIS, WEEK_MILLIS, YEAR_MILLIS,
+};
+
+#[doc(hidden)]
+pub const SECONDS_PER_DAY: i64 = 86400;
+
 /// gets the current time as milliseconds
 pub fn epoch_millis() -> Result<u128, Error> {
     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
     Ok(now.as_millis())
 }
 
-// helper method; if the value (time) is not 0, append to the string buffer
+/// A reading of one of the monotonic clocks, along with the boot it
+/// was read in, since they reset when the machine reboots
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootTime {
+    /// from /proc/sys/kernel/random/boot_id, changes every boot
+    pub boot_id: String,
+    /// milliseconds since boot, from the clock
+    pub millis: u128,
+}
+
+impl BootTime {
+    /// reads the current boot ID and CLOCK_BOOTTIME, which keeps counting
+    /// while suspended. `None` if either isn't available
+    #[cfg(target_os = "linux")]
+    pub fn now() -> Option<Self> {
+        Self::read(libc::CLOCK_BOOTTIME)
+    }
+
+    /// reads the current boot ID and CLOCK_MONOTONIC, which stops while suspended,
+    /// so it only counts the time the machine was running. `None` if either isn't available
+    ///
+    /// /proc/uptime can't be used for this, it includes the time spent suspended
+    #[cfg(target_os = "linux")]
+    pub fn active() -> Option<Self> {
+        Self::read(libc::CLOCK_MONOTONIC)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read(clock: libc::clockid_t) -> Option<Self> {
+        let boot_id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+        // SAFETY: timespec is plain old data, so all zeroes is a valid value
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        // SAFETY: ts is a valid timespec on the stack, which outlives the call
+        if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+            return None;
+        }
+        Some(Self {
+            boot_id: boot_id.trim().to_string(),
+            millis: ts.tv_sec as u128 * 1000 + ts.tv_nsec as u128 / 1_000_000,
+        })
+    }
+
+    /// the boot ID is linux-only, so this always falls back to wall time
+    #[cfg(not(target_os = "linux"))]
+    pub fn now() -> Option<Self> {
+        None
+    }
+
+    /// the boot ID is linux-only, so this always falls back to wall time
+    #[cfg(not(target_os = "linux"))]
+    pub fn active() -> Option<Self> {
+        None
+    }
+
+    /// milliseconds since an earlier reading, `None` if it was from a different boot
+    pub fn since(&self, earlier: &BootTime) -> Option<u128> {
+        if self.boot_id != earlier.boot_id {
+            return None;
+        }
+        self.millis.checked_sub(earlier.millis)
+    }
+}
+
+/// The clocks read when evry runs, saved to the tag file when it runs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Clocks {
+    /// CLOCK_BOOTTIME, if --monotonic was passed
+    pub boot: Option<BootTime>,
+    /// CLOCK_MONOTONIC, if --active was passed
+    pub active: Option<BootTime>,
+}
+
+/// picks a random number of milliseconds in `0..max`, e.g. to spread out when new jobs first run
+///
+/// Uses the random keys std seeds its hash maps with, which is plenty for scheduling
+pub fn random_millis_below(max: u128) -> u128 {
+    if max == 0 {
+        return 0;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    random as u128 % max
+}
+
+/// the units durations are described with, from largest to smallest:
+/// (milliseconds, long name, abbreviation from the evry grammar)
 #[doc(hidden)]
-fn add_part(parts: &mut Vec<String>, time: u128, description: &str) {
-    match time {
-        0 => (),
-        1 => parts.push(format!("{} {}", time, description)),
-        _ => parts.push(format!("{} {}s", time, description)),
+const UNITS: [(u128, &str, &str); 7] = [
+    (YEAR_MILLIS, "year", "y"),
+    (MONTH_MILLIS, "month", "mo"),
+    (WEEK_MILLIS, "week", "w"),
+    (DAY_MILLIS, "day", "d"),
+    (HOUR_MILLIS, "hour", "h"),
+    (MINUTE_MILLIS, "minute", "m"),
+    (SECOND_MILLIS, "second", "s"),
+];
+
+/// How to describe a duration to the user
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DurationFormat {
+    /// `1 hour, 58 minutes, 17 seconds`
+    #[default]
+    Long,
+    /// `1h58m17s`
+    Compact,
+    /// only the largest N units, rounded, like `~2 hours`
+    Largest(usize),
+    /// `1 hour 58 minutes 17 seconds`, can be passed back to evry/`parse_schedule`
+    Evry,
+}
+
+impl FromStr for DurationFormat {
+    type Err = Error;
+
+    /// parses `long`, `compact`, `largest`, `largest:N` or `evry`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "long" => Ok(DurationFormat::Long),
+            "compact" => Ok(DurationFormat::Compact),
+            "largest" => Ok(DurationFormat::Largest(1)),
+            "evry" => Ok(DurationFormat::Evry),
+            _ => match s.strip_prefix("largest:").map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => Ok(DurationFormat::Largest(n)),
+                _ => bail!(
+                    "Unknown duration format '{}', expected one of long, compact, largest, largest:N, evry",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+impl DurationFormat {
+    /// convert milliseconds to human readable time in this format
+    pub fn format(&self, ms: u128) -> String {
+        match self {
+            DurationFormat::Long => describe_ms(ms),
+            DurationFormat::Compact => {
+                join_parts(ms, |n, _, abbrev| format!("{}{}", n, abbrev), "")
+            }
+            DurationFormat::Largest(n) => describe_largest(ms, *n),
+            DurationFormat::Evry => join_parts(ms, |n, name, _| pluralize(n, name), " "),
+        }
+    }
+}
+
+/// helper method; formats a count of some unit, e.g. `1 hour`, `2 hours`
+fn pluralize(count: u128, name: &str) -> String {
+    match count {
+        1 => format!("{} {}", count, name),
+        _ => format!("{} {}s", count, name),
+    }
+}
+
+/// splits milliseconds into each unit, skipping any units which are 0.
+/// any milliseconds less than a second are dropped
+fn split_units(ms: u128) -> Vec<(u128, &'static str, &'static str)> {
+    let mut remaining = ms;
+    let mut parts = vec![];
+    for (unit_ms, name, abbrev) in UNITS {
+        let count = remaining / unit_ms;
+        remaining %= unit_ms;
+        if count > 0 {
+            parts.push((count, name, abbrev));
+        }
     }
+    parts
+}
+
+/// formats each non-zero unit with `fmt`, joined with `delim`.
+/// If the duration is less than a second, this describes it as 0 seconds
+fn join_parts(ms: u128, fmt: impl Fn(u128, &str, &str) -> String, delim: &str) -> String {
+    let parts = split_units(ms);
+    if parts.is_empty() {
+        let (_, name, abbrev) = UNITS[UNITS.len() - 1];
+        return fmt(0, name, abbrev);
+    }
+    parts
+        .into_iter()
+        .map(|(count, name, abbrev)| fmt(count, name, abbrev))
+        .collect::<Vec<String>>()
+        .join(delim)
 }
 
 /// convert milliseconds to human readable time,
@@ -23,29 +206,228 @@ fn add_part(parts: &mut Vec<String>, time: u128, description: &str) {
 ///
 /// Example:
 ///
-/// Converts 4799805877 (time in milliseconds) to '55 days, 13 hours, 16 minutes, 45 seconds'
+/// Converts 4799805877 (time in milliseconds) to '1 month, 3 weeks, 4 days, 13 hours, 16 minutes, 45 seconds'
 pub fn describe_ms(ms: u128) -> String {
-    let mut parts: Vec<String> = vec![];
-    // convert to seconds to begin with
-    let mut sec = ms / 1000;
-    // if more than a minute
-    if sec >= 60 {
-        let mut min = sec / 60;
-        sec %= 60;
-        // if more than an hour
-        if min >= 60 {
-            let mut hrs = min / 60;
-            min %= 60;
-            // if more than a day
-            if hrs >= 24 {
-                let days = hrs / 24;
-                hrs %= 24;
-                add_part(&mut parts, days, "day");
-            }
-            add_part(&mut parts, hrs, "hour");
+    join_parts(ms, |n, name, _| pluralize(n, name), ", ")
+}
+
+/// describes only the largest `units` units, rounding the smallest one.
+/// Prefixed with a '~' if the description isn't exact
+///
+/// Converts 7097748 (1 hour, 58 minutes, 17 seconds) to '~2 hours'
+fn describe_largest(ms: u128, units: usize) -> String {
+    let largest = UNITS
+        .iter()
+        .position(|(unit_ms, _, _)| ms >= *unit_ms)
+        .unwrap_or(UNITS.len() - 1);
+    let smallest = UNITS[(largest + units - 1).min(UNITS.len() - 1)].0;
+    // round to the nearest multiple of the smallest unit
+    let rounded = (ms + smallest / 2) / smallest * smallest;
+    let description = describe_ms(rounded);
+    if rounded / SECOND_MILLIS == ms / SECOND_MILLIS {
+        description
+    } else {
+        format!("~{}", description)
+    }
+}
+
+/// converts days since the epoch to a (year, month, day) in the proleptic Gregorian calendar
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097); // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32; // [1, 31]
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32; // [1, 12]
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// converts a (year, month, day) in the proleptic Gregorian calendar to days since the epoch
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400); // [0, 399]
+    let mp = (month as i64 + 9) % 12; // [0, 11], March is 0
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+/// the day of the week (0 is Sunday) for some days since the epoch
+pub fn weekday(day: i64) -> usize {
+    // 1970-01-01 was a Thursday
+    (day + 4).rem_euclid(7) as usize
+}
+
+/// offset of the local timezone from UTC (in seconds) at some epoch time
+#[cfg(unix)]
+pub fn local_offset_seconds(epoch_seconds: i64) -> i64 {
+    let time = epoch_seconds as libc::time_t;
+    // SAFETY: tm is plain old data, so all zeroes is a valid value
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // localtime_r is the thread-safe version of localtime, reads TZ/the system timezone
+    // SAFETY: both pointers are to valid values on the stack, which outlive the call
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+/// offset of the local timezone from UTC (in seconds) at some epoch time
+#[cfg(not(unix))]
+pub fn local_offset_seconds(_epoch_seconds: i64) -> i64 {
+    0
+}
+
+/// formats epoch milliseconds as an RFC 3339 timestamp, shifted
+/// by some offset (in seconds) from UTC
+///
+/// Converts 1697592310021 with an offset of 0 to '2023-10-18T01:25:10Z',
+/// or with an offset of -25200 to '2023-10-17T18:25:10-07:00'
+pub fn format_rfc3339(ms: u128, offset_seconds: i64) -> String {
+    let local_seconds = (ms / 1000) as i64 + offset_seconds;
+    let (year, month, day) = civil_from_days(local_seconds.div_euclid(SECONDS_PER_DAY));
+    let seconds_of_day = local_seconds.rem_euclid(SECONDS_PER_DAY);
+    let offset = if offset_seconds == 0 {
+        "Z".to_string()
+    } else {
+        let sign = if offset_seconds < 0 { '-' } else { '+' };
+        let offset_minutes = offset_seconds.abs() / 60;
+        format!(
+            "{}{:02}:{:02}",
+            sign,
+            offset_minutes / 60,
+            offset_minutes % 60
+        )
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60,
+        offset
+    )
+}
+
+/// formats epoch milliseconds as an RFC 3339 timestamp in UTC
+pub fn rfc3339_utc(ms: u128) -> String {
+    format_rfc3339(ms, 0)
+}
+
+/// formats epoch milliseconds as an RFC 3339 timestamp in the local timezone
+pub fn rfc3339_local(ms: u128) -> String {
+    format_rfc3339(ms, local_offset_seconds((ms / 1000) as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::parse_schedule;
+
+    #[test]
+    fn test_boot_time_since() {
+        let boot = |boot_id: &str, millis| BootTime {
+            boot_id: boot_id.to_string(),
+            millis,
+        };
+        assert_eq!(boot("a", 5_000).since(&boot("a", 2_000)), Some(3_000));
+        assert_eq!(boot("b", 5_000).since(&boot("a", 2_000)), None);
+        assert_eq!(boot("a", 2_000).since(&boot("a", 5_000)), None);
+    }
+
+    #[test]
+    fn test_describe_ms() {
+        assert_eq!(describe_ms(7097748), "1 hour, 58 minutes, 17 seconds");
+        assert_eq!(
+            describe_ms(4799805877),
+            "1 month, 3 weeks, 4 days, 13 hours, 16 minutes, 45 seconds"
+        );
+        assert_eq!(describe_ms(999), "0 seconds");
+    }
+
+    #[test]
+    fn test_duration_formats() {
+        assert_eq!(DurationFormat::Compact.format(7097748), "1h58m17s");
+        assert_eq!(DurationFormat::Largest(1).format(7097748), "~2 hours");
+        assert_eq!(
+            DurationFormat::Largest(2).format(7097748),
+            "~1 hour, 58 minutes"
+        );
+        assert_eq!(DurationFormat::Largest(1).format(7200000), "2 hours");
+        // rounding carries into the next unit
+        assert_eq!(DurationFormat::Largest(2).format(3599999), "~1 hour");
+        assert_eq!(
+            DurationFormat::Evry.format(7097748),
+            "1 hour 58 minutes 17 seconds"
+        );
+        assert_eq!(
+            "largest:3".parse::<DurationFormat>().unwrap(),
+            DurationFormat::Largest(3)
+        );
+        assert!("largest:0".parse::<DurationFormat>().is_err());
+        assert!("short".parse::<DurationFormat>().is_err());
+    }
+
+    #[test]
+    fn test_formats_roundtrip() {
+        for ms in [0, 1000, 7097000, 4799805000, YEAR_MILLIS * 3 + DAY_MILLIS] {
+            assert_eq!(
+                parse_schedule(&DurationFormat::Evry.format(ms))
+                    .unwrap()
+                    .every,
+                ms
+            );
+            assert_eq!(
+                parse_schedule(&DurationFormat::Compact.format(ms))
+                    .unwrap()
+                    .every,
+                ms
+            );
+            assert_eq!(
+                parse_schedule(&DurationFormat::Long.format(ms))
+                    .unwrap()
+                    .every,
+                ms
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0, 0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(1697592310021, 0), "2023-10-18T01:25:10Z");
+        assert_eq!(
+            format_rfc3339(1697592310021, -7 * 3600),
+            "2023-10-17T18:25:10-07:00"
+        );
+        assert_eq!(
+            format_rfc3339(1697592310021, 5 * 3600 + 1800),
+            "2023-10-18T06:55:10+05:30"
+        );
+        // leap day
+        assert_eq!(format_rfc3339(951782400000, 0), "2000-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(4107542399000, 0), "2100-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 2, 29), 11016);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-800_000, -1, 0, 11016, 19648, 2_000_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
         }
-        add_part(&mut parts, min, "minute");
     }
-    add_part(&mut parts, sec, "second");
-    parts.join(", ")
 }
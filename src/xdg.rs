@@ -0,0 +1,86 @@
+//! Resolves the base directories evry saves files to, following the
+//! [XDG Base Directory specification](https://specifications.freedesktop.org/basedir-spec/latest/)
+
+use anyhow::{Context, Error, Result};
+use std::{env, ffi::OsString, path::PathBuf};
+
+/// the users home directory (`$HOME`), used to compute the fallback directories
+fn home_dir(home: Option<OsString>) -> Result<PathBuf, Error> {
+    home.filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .context("Couldn't determine home directory, set the HOME or EVRY_DIR environment variable")
+}
+
+/// the users home directory, from `$HOME`
+#[cfg(target_os = "macos")]
+pub fn home() -> Result<PathBuf, Error> {
+    home_dir(env::var_os("HOME"))
+}
+
+/// uses the directory from the environment variable if its set, else `$HOME/<fallback>`
+fn resolve(var: &str, fallback: &str) -> Result<PathBuf, Error> {
+    resolve_from(env::var_os(var), env::var_os("HOME"), fallback)
+}
+
+/// uses `dir` (the value of an environment variable) if its set, else `<home>/<fallback>`
+///
+/// The spec says relative paths should be ignored, so those fall back as well
+fn resolve_from(
+    dir: Option<OsString>,
+    home: Option<OsString>,
+    fallback: &str,
+) -> Result<PathBuf, Error> {
+    match dir.map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => Ok(dir),
+        _ => Ok(home_dir(home)?.join(fallback)),
+    }
+}
+
+/// `$XDG_DATA_HOME`, or `~/.local/share`
+pub fn data_home() -> Result<PathBuf, Error> {
+    resolve("XDG_DATA_HOME", ".local/share")
+}
+
+/// `$XDG_STATE_HOME`, or `~/.local/state`
+pub fn state_home() -> Result<PathBuf, Error> {
+    resolve("XDG_STATE_HOME", ".local/state")
+}
+
+/// `$XDG_CACHE_HOME`, or `~/.cache`
+pub fn cache_home() -> Result<PathBuf, Error> {
+    resolve("XDG_CACHE_HOME", ".cache")
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config`
+pub fn config_home() -> Result<PathBuf, Error> {
+    resolve("XDG_CONFIG_HOME", ".config")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_from() {
+        let home = || Some(OsString::from("/home/user"));
+        assert_eq!(
+            resolve_from(Some("/xdg/state".into()), home(), ".local/state").unwrap(),
+            PathBuf::from("/xdg/state")
+        );
+        assert_eq!(
+            resolve_from(None, home(), ".local/state").unwrap(),
+            PathBuf::from("/home/user/.local/state")
+        );
+        // relative and empty paths are ignored
+        for dir in ["relative/state", ""] {
+            assert_eq!(
+                resolve_from(Some(dir.into()), home(), ".local/state").unwrap(),
+                PathBuf::from("/home/user/.local/state")
+            );
+        }
+        assert!(resolve_from(None, None, ".cache").is_err());
+        assert!(resolve_from(None, Some("".into()), ".cache").is_err());
+        // the home directory isn't needed if the variable is set
+        assert!(resolve_from(Some("/xdg/cache".into()), None, ".cache").is_ok());
+    }
+}